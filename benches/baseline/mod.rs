@@ -0,0 +1,189 @@
+//! The storage of ctxmap 0.5.0, kept to compare reading and setting values with the current implementation.
+//!
+//! Only the parts used by the benchmarks are reproduced: keys are initialized lazily,
+//! bound values are stored as `*const dyn Any` pointing to the pointer to the value, and
+//! default values are stored as `Box<dyn Any>`.
+
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock,
+    },
+};
+
+pub struct CtxMap {
+    ptrs: Vec<Option<*const dyn Any>>,
+    values: UnsafeCell<Vec<Option<Box<dyn Any>>>>,
+}
+
+impl CtxMap {
+    pub fn new() -> Self {
+        Self {
+            ptrs: Vec::new(),
+            values: UnsafeCell::new(Vec::new()),
+        }
+    }
+    pub fn with<T: ?Sized + 'static, U>(
+        &mut self,
+        key: &'static Key<T>,
+        value: &T,
+        f: impl FnOnce(&mut CtxMap) -> U,
+    ) -> U {
+        let ptr: *const T = value;
+        self.with_impl(key, ptr, f)
+    }
+    pub fn with_mut<T: ?Sized + 'static, U, const MUT: bool>(
+        &mut self,
+        key: &'static Key<T, MUT>,
+        value: &mut T,
+        f: impl FnOnce(&mut CtxMap) -> U,
+    ) -> U {
+        let ptr: *mut T = value;
+        self.with_impl(key, ptr, f)
+    }
+    fn with_impl<T: ?Sized, U, P: 'static, const MUT: bool>(
+        &mut self,
+        key: &'static Key<T, MUT>,
+        ptr: P,
+        f: impl FnOnce(&mut CtxMap) -> U,
+    ) -> U {
+        let index = key.0.index;
+        if self.ptrs.len() <= index {
+            self.ptrs.resize_with(index + 1, || None);
+        }
+        let old = self.ptrs[index];
+        self.ptrs[index] = Some(&ptr);
+        let retval = f(self);
+        self.ptrs[index] = old;
+        retval
+    }
+    pub fn get<T: ?Sized + 'static, const MUT: bool>(
+        &self,
+        key: &'static Key<T, MUT>,
+    ) -> Option<&T> {
+        let key = &*key.0;
+        let index = key.index;
+        unsafe {
+            if let Some(Some(p)) = self.ptrs.get(index) {
+                if let Some(p) = <dyn Any>::downcast_ref::<*const T>(&**p) {
+                    Some(&**p)
+                } else if let Some(p) = <dyn Any>::downcast_ref::<*mut T>(&**p) {
+                    Some(&**p)
+                } else {
+                    unreachable!()
+                }
+            } else {
+                let data = key.data.as_ref()?.as_ref();
+                loop {
+                    if let Some(Some(value)) = (&*self.values.get()).get(index) {
+                        let p: *const dyn Any = value.as_ref();
+                        return Some(data.get(&*p));
+                    }
+                    self.init_value(index, data);
+                }
+            }
+        }
+    }
+    pub fn get_mut<T: ?Sized + 'static>(&mut self, key: &'static Key<T, true>) -> Option<&mut T> {
+        let key = &*key.0;
+        let index = key.index;
+        unsafe {
+            if let Some(Some(p)) = self.ptrs.get(index) {
+                Some(&mut **<dyn Any>::downcast_ref::<*mut T>(&**p).unwrap())
+            } else {
+                let data = key.data.as_ref()?.as_ref();
+                loop {
+                    if let Some(Some(value)) = (&mut *self.values.get()).get_mut(index) {
+                        let p: *mut dyn Any = value.as_mut();
+                        return Some(data.get_mut(&mut *p));
+                    }
+                    self.init_value(index, data);
+                }
+            }
+        }
+    }
+    unsafe fn init_value<T: ?Sized>(&self, index: usize, data: &dyn KeyData<T>) {
+        let init = data.init();
+        let values = &mut *self.values.get();
+        if values.len() <= index {
+            values.resize_with(index + 1, || None);
+        }
+        values[index] = Some(init);
+    }
+}
+
+pub struct Key<T: ?Sized + 'static, const MUT: bool = false>(pub LazyLock<RawKey<T>>);
+
+pub struct RawKey<T: ?Sized + 'static> {
+    index: usize,
+    data: Option<Box<dyn KeyData<T>>>,
+}
+
+impl<T: ?Sized + 'static> RawKey<T> {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            index: NEXT.fetch_add(1, Ordering::SeqCst),
+            data: None,
+        }
+    }
+    pub fn new_with<V: 'static>(
+        init: fn() -> V,
+        to_ref: fn(&V) -> &T,
+        to_mut: fn(&mut V) -> &mut T,
+    ) -> Self {
+        Self {
+            data: Some(Box::new(KeyDataValue {
+                init,
+                to_ref,
+                to_mut,
+            })),
+            ..Self::new()
+        }
+    }
+}
+
+trait KeyData<T: ?Sized>: Send + Sync {
+    fn get<'a>(&self, value: &'a dyn Any) -> &'a T;
+    fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T;
+    fn init(&self) -> Box<dyn Any>;
+}
+
+struct KeyDataValue<V, T: ?Sized> {
+    init: fn() -> V,
+    to_ref: fn(&V) -> &T,
+    to_mut: fn(&mut V) -> &mut T,
+}
+
+impl<V: 'static, T: ?Sized> KeyData<T> for KeyDataValue<V, T> {
+    fn get<'a>(&self, value: &'a dyn Any) -> &'a T {
+        (self.to_ref)(<dyn Any>::downcast_ref::<V>(value).unwrap())
+    }
+    fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T {
+        (self.to_mut)(<dyn Any>::downcast_mut::<V>(value).unwrap())
+    }
+    fn init(&self) -> Box<dyn Any> {
+        Box::new((self.init)())
+    }
+}
+
+/// Defines a key of the baseline implementation like [`ctxmap::key`].
+macro_rules! key {
+    ($id:ident: $t:ty) => {
+        static $id: baseline::Key<$t> =
+            baseline::Key(std::sync::LazyLock::new(baseline::RawKey::new));
+    };
+    ($id:ident: $t:ty = $v:expr) => {
+        static $id: baseline::Key<$t> = baseline::Key(std::sync::LazyLock::new(|| {
+            baseline::RawKey::new_with(|| $v, |x| x, |_| unreachable!())
+        }));
+    };
+    (mut $id:ident: $t:ty = $v:expr) => {
+        static $id: baseline::Key<$t, true> = baseline::Key(std::sync::LazyLock::new(|| {
+            baseline::RawKey::new_with(|| $v, |x| x, |x| x)
+        }));
+    };
+}
+pub(crate) use key;
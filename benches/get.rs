@@ -0,0 +1,119 @@
+//! Benchmarks of reading and setting values.
+//!
+//! Each benchmark of a dense schema is run on the current implementation and on [`baseline`],
+//! a copy of the storage of ctxmap 0.5.0, so that a regression is visible in a single run.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ctxmap::CtxMap;
+use std::hint::black_box;
+
+mod baseline;
+
+ctxmap::schema!(S);
+ctxmap::key!(S {
+    KEY_BOUND: u32,
+    KEY_DEFAULT: u32 = 10,
+    KEY_MISSING: u32,
+    KEY_STR: str = "abc",
+    mut KEY_MUT: u32 = 20,
+});
+
+baseline::key!(BASE_BOUND: u32);
+baseline::key!(BASE_DEFAULT: u32 = 10);
+baseline::key!(BASE_MISSING: u32);
+baseline::key!(BASE_STR: str = "abc");
+baseline::key!(mut BASE_MUT: u32 = 20);
+
+ctxmap::schema!(sparse Sparse);
+ctxmap::key!(Sparse {
+    SPARSE_BOUND: u32,
+    SPARSE_DEFAULT: u32 = 10,
+});
+
+fn get(c: &mut Criterion) {
+    let mut m = CtxMap::<S>::new();
+    assert_eq!(m[&KEY_DEFAULT], 10);
+    assert_eq!(m[&KEY_MUT], 20);
+    let mut base = baseline::CtxMap::new();
+    assert_eq!(base.get(&BASE_DEFAULT), Some(&10));
+    assert_eq!(base.get(&BASE_MUT), Some(&20));
+    m.with(&KEY_BOUND, &1, |m| {
+        m.with(&KEY_STR, "xyz", |m| {
+            base.with(&BASE_BOUND, &1, |base| {
+                base.with(&BASE_STR, "xyz", |base| {
+                    let mut g = c.benchmark_group("get_bound");
+                    g.bench_function("current", |b| b.iter(|| m.get(black_box(&KEY_BOUND))));
+                    g.bench_function("baseline", |b| b.iter(|| base.get(black_box(&BASE_BOUND))));
+                    g.finish();
+
+                    let mut g = c.benchmark_group("get_bound_dst");
+                    g.bench_function("current", |b| b.iter(|| m.get(black_box(&KEY_STR))));
+                    g.bench_function("baseline", |b| b.iter(|| base.get(black_box(&BASE_STR))));
+                    g.finish();
+
+                    let mut g = c.benchmark_group("get_default");
+                    g.bench_function("current", |b| b.iter(|| m.get(black_box(&KEY_DEFAULT))));
+                    g.bench_function("baseline", |b| {
+                        b.iter(|| base.get(black_box(&BASE_DEFAULT)))
+                    });
+                    g.finish();
+
+                    let mut g = c.benchmark_group("get_missing");
+                    g.bench_function("current", |b| b.iter(|| m.get(black_box(&KEY_MISSING))));
+                    g.bench_function("baseline", |b| {
+                        b.iter(|| base.get(black_box(&BASE_MISSING)))
+                    });
+                    g.finish();
+
+                    let mut g = c.benchmark_group("get_mut_default");
+                    g.bench_function("current", |b| {
+                        b.iter(|| *m.get_mut(black_box(&KEY_MUT)).unwrap() += 1)
+                    });
+                    g.bench_function("baseline", |b| {
+                        b.iter(|| *base.get_mut(black_box(&BASE_MUT)).unwrap() += 1)
+                    });
+                    g.finish();
+                });
+            });
+        });
+    });
+}
+
+fn get_sparse(c: &mut Criterion) {
+    let mut m = CtxMap::<Sparse>::new();
+    assert_eq!(m[&SPARSE_DEFAULT], 10);
+    m.with(&SPARSE_BOUND, &1, |m| {
+        c.bench_function("get_bound_sparse", |b| {
+            b.iter(|| m.get(black_box(&SPARSE_BOUND)))
+        });
+        c.bench_function("get_default_sparse", |b| {
+            b.iter(|| m.get(black_box(&SPARSE_DEFAULT)))
+        });
+    });
+}
+
+fn with(c: &mut Criterion) {
+    let mut m = CtxMap::<S>::new();
+    let mut base = baseline::CtxMap::new();
+
+    let mut g = c.benchmark_group("with");
+    g.bench_function("current", |b| {
+        b.iter(|| m.with(&KEY_BOUND, black_box(&1), |m| m[&KEY_BOUND]))
+    });
+    g.bench_function("baseline", |b| {
+        b.iter(|| base.with(&BASE_BOUND, black_box(&1), |m| *m.get(&BASE_BOUND).unwrap()))
+    });
+    g.finish();
+
+    let mut g = c.benchmark_group("with_mut");
+    g.bench_function("current", |b| {
+        b.iter(|| m.with_mut(&KEY_MUT, black_box(&mut 1), |m| m[&KEY_MUT]))
+    });
+    g.bench_function("baseline", |b| {
+        b.iter(|| base.with_mut(&BASE_MUT, black_box(&mut 1), |m| *m.get(&BASE_MUT).unwrap()))
+    });
+    g.finish();
+}
+
+criterion_group!(benches, get, get_sparse, with);
+criterion_main!(benches);
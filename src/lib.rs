@@ -49,20 +49,81 @@
 
 use helpers::*;
 use std::{
-    any::Any,
-    cell::UnsafeCell,
-    marker::PhantomData,
+    any::{Any, TypeId},
+    cell::{Cell, RefCell, UnsafeCell},
+    error::Error,
+    fmt,
+    future::Future,
+    hint::unreachable_unchecked,
+    marker::{PhantomData, PhantomPinned},
     ops::{Index, IndexMut},
-    sync::LazyLock,
+    panic::{RefUnwindSafe, UnwindSafe},
+    pin::Pin,
+    ptr::NonNull,
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    thread::{self, ScopedJoinHandle},
 };
 
+/// A default value and the generation of the key that initialized it.
+///
+/// `ptr` points to the value as the value type of the key, so reading it needs no downcast.
+struct Slot {
+    generation: usize,
+    value: *mut dyn Any,
+    ptr: RawPtr,
+}
+
+impl Slot {
+    fn new<S: Schema, T: ?Sized>(
+        generation: usize,
+        value: Box<dyn Any>,
+        data: &dyn KeyData<S, T>,
+        mutable: bool,
+    ) -> Self {
+        let value = Box::into_raw(value);
+        let ptr: *const T = unsafe {
+            if mutable {
+                let p: *mut T = data.get_mut(&mut *value);
+                p
+            } else {
+                data.get(&*value)
+            }
+        };
+        Self {
+            generation,
+            value,
+            ptr: RawPtr::new(ptr),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.value) });
+    }
+}
+
 /// A collection that can store references of different types and lifetimes.
 pub struct CtxMap<S: Schema> {
     schema: PhantomData<S>,
-    ptrs: Vec<Option<*const dyn Any>>,
-    values: UnsafeCell<Vec<Option<Box<dyn Any>>>>,
+    ptrs: TableOf<S, Bound>,
+    /// The length of `ptrs` if it is dense and no value of the map is being computed, otherwise 0.
+    ///
+    /// Indices below it can be read from `ptrs` without resolving the index or tracking dependencies.
+    fast_len: AtomicUsize,
+    values: UnsafeCell<TableOf<S, Slot>>,
+    computed: UnsafeCell<Vec<Computed>>,
+    tracking: AtomicUsize,
+    lock: S::Lock,
 }
 
+type TableOf<S, T> = <<S as Schema>::Storage as Storage>::Table<T>;
+
 impl<S: Schema> CtxMap<S> {
     /// Create a new `CtxMap` with initial values.
     ///
@@ -81,13 +142,19 @@ impl<S: Schema> CtxMap<S> {
     pub fn new() -> Self {
         Self {
             schema: PhantomData,
-            values: UnsafeCell::new(Vec::new()),
-            ptrs: Vec::new(),
+            values: UnsafeCell::new(Table::new()),
+            computed: UnsafeCell::new(Vec::new()),
+            ptrs: Table::new(),
+            fast_len: AtomicUsize::new(0),
+            tracking: AtomicUsize::new(0),
+            lock: S::Lock::new(),
         }
     }
 
     /// Sets a value corresponding to the key only while `f` is being called.
     ///
+    /// The previous value is restored even if `f` panics.
+    ///
     /// # Example
     ///
     /// ```
@@ -138,338 +205,3213 @@ impl<S: Schema> CtxMap<S> {
         self.view().with_mut(key, value, f)
     }
 
-    /// Get [`CtxMapView`] that references `self`.
-    pub fn view(&mut self) -> CtxMapView<'_, S> {
-        CtxMapView(self)
-    }
-
-    /// Returns a reference to the value corresponding to the key.
+    /// Appends an item to the slice corresponding to the key only while `f` is being called.
+    ///
+    /// The value seen in `f` is the current value followed by `item`.
+    /// Keys declared with `acc` have an empty slice as the default value.
+    ///
+    /// The current value is cloned into a new slice on each call, so `N` nested calls clone `O(N²)` items in total.
+    /// For deeply nested scopes, consider setting each item with [`with`](Self::with) and reading them with [`stack`](Self::stack).
     ///
     /// # Example
     ///
     /// ```
     /// ctxmap::schema!(S);
-    /// ctxmap::key!(S { KEY_A: u16 });
+    /// ctxmap::key!(S { acc KEY_PATH: [&'static str] });
     ///
     /// let mut m = ctxmap::CtxMap::new();
-    /// assert_eq!(m.get(&KEY_A), None);
-    /// m.with(&KEY_A, &10, |m| {
-    ///     assert_eq!(m.get(&KEY_A), Some(&10));
+    /// m.with_append(&KEY_PATH, "server", |m| {
+    ///     m.with_append(&KEY_PATH, "db", |m| {
+    ///         assert_eq!(m[&KEY_PATH], ["server", "db"]);
+    ///     });
+    ///     assert_eq!(m[&KEY_PATH], ["server"]);
     /// });
-    /// assert_eq!(m.get(&KEY_A), None);
+    /// assert!(m[&KEY_PATH].is_empty());
     /// ```
-    pub fn get<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> Option<&T> {
-        let key = &*key.0;
-        let index = key.index;
-        unsafe {
-            if let Some(Some(p)) = self.ptrs.get(index) {
-                if let Some(p) = <dyn Any>::downcast_ref::<*const T>(&**p) {
-                    Some(&**p)
-                } else if let Some(p) = <dyn Any>::downcast_ref::<*mut T>(&**p) {
-                    Some(&**p)
-                } else {
-                    unreachable!()
-                }
-            } else {
-                let data = key.data.as_ref()?.as_ref();
-                loop {
-                    if let Some(Some(value)) = (&*self.values.get()).get(index) {
-                        let p: *const dyn Any = value.as_ref();
-                        return Some(data.get(&*p));
-                    }
-                    self.init_value(index, data);
-                }
-            }
-        }
+    pub fn with_append<T: Clone + 'static, U>(
+        &mut self,
+        key: &'static Key<S, [T]>,
+        item: T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        self.view().with_append(key, item, f)
     }
 
-    /// Returns a mutable reference to the value corresponding to the key.
+    /// Sets a value keyed by its type only while `f` is being called.
+    ///
+    /// Values keyed by types can be read with [`get_type`](Self::get_type) without defining a key with [`key`] macro.
+    /// Each type has one key per schema, which is separate from the keys defined with [`key`] macro.
     ///
     /// # Example
     ///
     /// ```
+    /// struct DbPool(u32);
+    ///
     /// ctxmap::schema!(S);
-    /// ctxmap::key!(S { mut KEY_A: u16 });
     ///
-    /// let mut m = ctxmap::CtxMap::new();
-    /// assert_eq!(m.get_mut(&KEY_A), None);
-    /// m.with_mut(&KEY_A, &mut 10, |m| {
-    ///     assert_eq!(m.get_mut(&KEY_A), Some(&mut 10));
+    /// let mut m = ctxmap::CtxMap::<S>::new();
+    /// assert!(m.get_type::<DbPool>().is_none());
+    /// m.with_type(&DbPool(10), |m| {
+    ///     assert_eq!(m.get_type::<DbPool>().unwrap().0, 10);
     /// });
-    /// assert_eq!(m.get_mut(&KEY_A), None);
+    /// assert!(m.get_type::<DbPool>().is_none());
     /// ```
-    pub fn get_mut<T: ?Sized>(&mut self, key: &'static KeyMut<S, T>) -> Option<&mut T> {
-        let key = &*key.0;
-        let index = key.index;
-        unsafe {
-            if let Some(Some(p)) = self.ptrs.get(index) {
-                Some(&mut **<dyn Any>::downcast_ref::<*mut T>(&**p).unwrap())
-            } else {
-                let data = key.data.as_ref()?.as_ref();
-                loop {
-                    if let Some(Some(value)) = (&mut *self.values.get()).get_mut(index) {
-                        let p: *mut dyn Any = value.as_mut();
-                        return Some(data.get_mut(&mut *p));
-                    }
-                    self.init_value(index, data);
-                }
-            }
-        }
-    }
-    unsafe fn init_value<T: ?Sized>(&self, index: usize, data: &dyn KeyData<T>) {
-        let init = data.init();
-        let values = &mut *self.values.get();
-        if values.len() <= index {
-            values.resize_with(index + 1, || None);
-        }
-        values[index] = Some(init);
+    pub fn with_type<T: ?Sized + 'static, U>(
+        &mut self,
+        value: &T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U
+    where
+        S: ValueBound<T, false>,
+    {
+        self.view().with_type(value, f)
     }
-}
 
-impl<S: Schema> Default for CtxMap<S> {
-    fn default() -> Self {
-        Self::new()
+    /// Sets a value corresponding to the runtime key only while `f` is being called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::DynKey;
+    ///
+    /// ctxmap::schema!(S);
+    ///
+    /// let key = DynKey::<S, u16>::new();
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m.get_dyn(&key), None);
+    /// m.with_dyn(&key, &30, |m| {
+    ///     assert_eq!(m.get_dyn(&key), Some(&30));
+    /// });
+    /// assert_eq!(m.get_dyn(&key), None);
+    /// ```
+    pub fn with_dyn<T: ?Sized + 'static, U>(
+        &mut self,
+        key: &DynKey<S, T>,
+        value: &T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        self.view().with_dyn(key, value, f)
     }
-}
-impl<S, T, const MUT: bool> Index<&'static Key<S, T, MUT>> for CtxMap<S>
-where
-    S: Schema,
-    T: ?Sized,
-{
-    type Output = T;
 
-    fn index(&self, index: &'static Key<S, T, MUT>) -> &Self::Output {
-        self.get(index).expect("no entry found for key")
-    }
-}
-impl<S, T> IndexMut<&'static KeyMut<S, T>> for CtxMap<S>
-where
-    S: Schema,
-    T: ?Sized,
-{
-    fn index_mut(&mut self, index: &'static KeyMut<S, T>) -> &mut Self::Output {
-        self.get_mut(index).expect("no entry found for key")
+    /// Makes `self` the current map of the thread only while `f` is being called.
+    ///
+    /// The current map can be read with [`current`] without passing `CtxMap` to every function.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 20 });
+    ///
+    /// fn read_a() -> u16 {
+    ///     ctxmap::current(|m| m[&KEY_A])
+    /// }
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// m.with(&KEY_A, &30, |m| {
+    ///     assert_eq!(m.enter(read_a), 30);
+    /// });
+    /// assert_eq!(read_a(), 20);
+    /// ```
+    pub fn enter<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        self.view().enter(f)
     }
-}
-
-/// Mutable reference to [`CtxMap`] where the value has changed.
-///
-/// Use `CtxMapView` instead of `&mut CtxMap` because `&mut CtxMap`,
-/// whose value has been changed, will be broken if [`std::mem::swap`] is used.
-pub struct CtxMapView<'a, S: Schema>(&'a mut CtxMap<S>);
 
-impl<S: Schema> CtxMapView<'_, S> {
-    /// Sets a value to `CtxMap` only while `f` is being called.
+    /// Sets a value corresponding to the key only while the future returned by `f` is being polled.
     ///
-    /// See [`CtxMap::with`] for more details.
-    pub fn with<T: ?Sized, U>(
+    /// The value is also visible across `.await` in `f`, and values borrowed from the map in `f` stay valid across `.await`.
+    /// When the returned future completes or is dropped, the previous value is restored.
+    /// Unlike [`bind_future`](Self::bind_future), `self` is not made the current map of the thread.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 20 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// let f = m.with_async(&KEY_A, &30, async |m| m[&KEY_A]);
+    /// let mut cx = Context::from_waker(Waker::noop());
+    /// assert_eq!(pin!(f).poll(&mut cx), Poll::Ready(30));
+    /// assert_eq!(m[&KEY_A], 20);
+    /// ```
+    pub async fn with_async<T: ?Sized, U>(
         &mut self,
         key: &'static Key<S, T>,
         value: &T,
-        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+        f: impl AsyncFnOnce(&mut CtxMapView<S>) -> U,
     ) -> U {
+        self.view().with_async(key, value, f).await
+    }
+
+    /// Returns a future that sets a value corresponding to the key only while `future` is being polled.
+    ///
+    /// While `future` is being polled, `self` is also the current map of the thread,
+    /// so the value can be read with [`current`] across `.await`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 20 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// let f = m.bind_future(&KEY_A, &30, async { ctxmap::current(|m| m[&KEY_A]) });
+    /// let mut cx = Context::from_waker(Waker::noop());
+    /// assert_eq!(pin!(f).poll(&mut cx), Poll::Ready(30));
+    /// assert_eq!(m[&KEY_A], 20);
+    /// ```
+    pub fn bind_future<'a, T: ?Sized, F: Future>(
+        &'a mut self,
+        key: &'static Key<S, T>,
+        value: &'a T,
+        future: F,
+    ) -> WithCtx<'a, S, F> {
         let ptr: *const T = value;
-        self.with_impl(key, ptr, f)
+        WithCtx {
+            map: self.view(),
+            binding: Some((key.index(), RawPtr::new(ptr))),
+            future,
+        }
     }
 
-    /// Sets a mutable value to `CtxMap` only while `f` is being called.
+    /// Sets multiple values corresponding to the keys only while `f` is being called.
     ///
-    /// See [`CtxMap::with_mut`] for more details.
-    pub fn with_mut<T: ?Sized, U, const MUT: bool>(
+    /// `bindings` is a pair of a key and a reference to a value, or a tuple of such pairs.
+    /// A pair with `&T` works like [`with`](Self::with), and a pair with `&mut T` works like [`with_mut`](Self::with_mut).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S {
+    ///     KEY_A: u16 = 10,
+    ///     KEY_B: str = "abc",
+    ///     mut KEY_C: u16 = 20,
+    /// });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// m.with_all(((&KEY_A, &30), (&KEY_B, "xyz"), (&KEY_C, &mut 40)), |m| {
+    ///     assert_eq!(m[&KEY_A], 30);
+    ///     assert_eq!(&m[&KEY_B], "xyz");
+    ///     m[&KEY_C] = 50;
+    ///     assert_eq!(m[&KEY_C], 50);
+    /// });
+    /// assert_eq!(m[&KEY_A], 10);
+    /// assert_eq!(&m[&KEY_B], "abc");
+    /// assert_eq!(m[&KEY_C], 20);
+    /// ```
+    pub fn with_all<U>(
         &mut self,
-        key: &'static Key<S, T, MUT>,
-        value: &mut T,
+        bindings: impl Bindings<S>,
         f: impl FnOnce(&mut CtxMapView<S>) -> U,
     ) -> U {
-        let ptr: *mut T = value;
-        self.with_impl(key, ptr, f)
+        self.view().with_all(bindings, f)
+    }
+
+    /// Returns a snapshot of the values currently set by [`with`](Self::with) and [`with_mut`](Self::with_mut).
+    ///
+    /// Only the values of keys defined with `clone` are captured.
+    /// The snapshot owns clones of the values, so it can be sent to other threads and tasks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { clone KEY_A: String = String::from("a") });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// let snapshot = m.with(&KEY_A, &String::from("b"), |m| m.snapshot());
+    /// assert_eq!(m[&KEY_A], "a");
+    /// m.with_snapshot(&snapshot, |m| {
+    ///     assert_eq!(m[&KEY_A], "b");
+    /// });
+    /// ```
+    pub fn snapshot(&self) -> CtxSnapshot<S> {
+        let data = S::data();
+        let mut entries = Vec::new();
+        for (index, b) in self.ptrs.iter() {
+            if let Some(f) = data.snapshot_fn::<S>(index) {
+                entries.push((index, unsafe { f(b.frame.as_ref()) }));
+            }
+        }
+        CtxSnapshot {
+            schema: PhantomData,
+            entries,
+        }
     }
 
-    fn with_impl<T: ?Sized, U, P: 'static, const MUT: bool>(
+    /// Sets the values captured in `snapshot` only while `f` is being called.
+    ///
+    /// See [`CtxMap::snapshot`] for more details.
+    pub fn with_snapshot<U>(
         &mut self,
-        key: &'static Key<S, T, MUT>,
-        ptr: P,
+        snapshot: &CtxSnapshot<S>,
         f: impl FnOnce(&mut CtxMapView<S>) -> U,
     ) -> U {
-        let key = &*key.0;
-        let index = key.index;
-        if self.0.ptrs.len() <= index {
-            self.0.ptrs.resize_with(index + 1, || None);
-        }
-        let old = self.0.ptrs[index];
-        self.0.ptrs[index] = Some(&ptr);
-        let retval = f(self);
-        self.0.ptrs[index] = old;
-        retval
+        self.view().with_snapshot(snapshot, f)
     }
 
-    /// Return `CtxMapView` with modified lifetime.
+    /// Get [`CtxMapView`] that references `self`.
     pub fn view(&mut self) -> CtxMapView<'_, S> {
-        CtxMapView(self.0)
+        CtxMapView(NonNull::from(self), PhantomData)
     }
 
     /// Returns a reference to the value corresponding to the key.
     ///
-    /// See [`CtxMap::get`] for more details.
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m.get(&KEY_A), None);
+    /// m.with(&KEY_A, &10, |m| {
+    ///     assert_eq!(m.get(&KEY_A), Some(&10));
+    /// });
+    /// assert_eq!(m.get(&KEY_A), None);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if initializing the default value fails. Use [`try_get`](Self::try_get) to handle the error.
+    #[inline]
     pub fn get<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> Option<&T> {
-        self.0.get(key)
+        unwrap_init(self.try_get(key))
     }
 
-    /// Returns a mutable reference to the value corresponding to the key.
+    /// Returns a reference to the value corresponding to the key, or an error if initializing the default value fails.
     ///
-    /// See [`CtxMap::get_mut`] for more details.
-    pub fn get_mut<T: ?Sized>(&mut self, key: &'static KeyMut<S, T>) -> Option<&mut T> {
-        self.0.get_mut(key)
+    /// A failed initialization is not cached, and is retried on the next access.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = try "x".parse::<u16>() });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert!(m.try_get(&KEY_A).is_err());
+    /// m.with(&KEY_A, &10, |m| {
+    ///     assert_eq!(m.try_get(&KEY_A).unwrap(), Some(&10));
+    /// });
+    /// ```
+    #[inline]
+    pub fn try_get<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<Option<&T>, InitError> {
+        match self.fast_entry(key.0.index.load(Ordering::Relaxed)) {
+            Some(Some(b)) => unsafe { Ok(Some(b.value())) },
+            Some(None) => self.get_default(key),
+            None => self.try_get_slow(key),
+        }
     }
-}
-
-impl<S, T, const MUT: bool> Index<&'static Key<S, T, MUT>> for CtxMapView<'_, S>
-where
-    S: Schema,
-    T: ?Sized,
-{
-    type Output = T;
-
-    fn index(&self, index: &'static Key<S, T, MUT>) -> &Self::Output {
-        &self.0[index]
+    fn try_get_slow<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<Option<&T>, InitError> {
+        let index = key.index();
+        let frame = self.frame(index);
+        self.track(&[(index, frame)]);
+        if let Some(p) = frame {
+            unsafe { Ok(Some(p.as_ref().value())) }
+        } else {
+            self.get_default(key)
+        }
     }
-}
-impl<S, T> IndexMut<&'static KeyMut<S, T>> for CtxMapView<'_, S>
-where
-    S: Schema,
-    T: ?Sized,
-{
-    fn index_mut(&mut self, index: &'static KeyMut<S, T>) -> &mut Self::Output {
-        &mut self.0[index]
+
+    /// Returns a reference to the value corresponding to the key, or an error describing the key if there is no value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if initializing the default value fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// let e = m.require(&KEY_A).unwrap_err();
+    /// assert_eq!(e.key().name(), "KEY_A");
+    /// m.with(&KEY_A, &10, |m| {
+    ///     assert_eq!(m.require(&KEY_A).unwrap(), &10);
+    /// });
+    /// ```
+    pub fn require<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<&T, MissingKey> {
+        self.get(key).ok_or(MissingKey(&key.1))
     }
-}
 
-/// A key for [`CtxMap`].
-///
-/// Use [`key`] macro to create `Key`.
-pub struct Key<S: Schema, T: ?Sized, const MUT: bool = false>(LazyLock<RawKey<S, T, MUT>>);
-pub type KeyMut<S, T> = Key<S, T, true>;
-trait KeyData<T: ?Sized>: Send + Sync {
-    fn get<'a>(&self, value: &'a dyn Any) -> &'a T;
-    fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T;
-    fn init(&self) -> Box<dyn Any>;
+    /// Returns a reference to the value corresponding to the runtime key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if initializing the default value fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::DynKey;
+    ///
+    /// ctxmap::schema!(S);
+    ///
+    /// let key = DynKey::<S, String>::with_default(|_| "abc".to_string());
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m.get_dyn(&key).unwrap(), "abc");
+    /// m.with_dyn(&key, &"xyz".to_string(), |m| {
+    ///     assert_eq!(m.get_dyn(&key).unwrap(), "xyz");
+    /// });
+    /// ```
+    pub fn get_dyn<'a, T: ?Sized + 'static>(&'a self, key: &'a DynKey<S, T>) -> Option<&'a T> {
+        let key = &*key.0;
+        let frame = self.frame(key.index);
+        self.track(&[(key.index, frame)]);
+        if let Some(p) = frame {
+            return unsafe { Some(p.as_ref().value()) };
+        }
+        let data = key.data.as_deref()?;
+        let p = unwrap_init(self.value_ptr(key.index, key.generation, None, data, false));
+        unsafe { Some(&*p.get()) }
+    }
+
+    /// Returns a reference to the value keyed by its type.
+    ///
+    /// See [`with_type`](Self::with_type) for more details.
+    pub fn get_type<T: ?Sized + 'static>(&self) -> Option<&T> {
+        self.get(type_key())
+    }
+
+    /// Returns a reference to the value keyed by its type, or the default value of the type if there is no value.
+    ///
+    /// The default value is created by [`Default::default`] at the first access, like default values of keys.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    ///
+    /// let mut m = ctxmap::CtxMap::<S>::new();
+    /// assert_eq!(m.get_type_or_default::<u32>(), &0);
+    /// m.with_type(&10u32, |m| {
+    ///     assert_eq!(m.get_type_or_default::<u32>(), &10);
+    /// });
+    /// ```
+    pub fn get_type_or_default<T: Default + 'static>(&self) -> &T
+    where
+        S: ValueBound<T, false> + InitBound<T>,
+    {
+        match self.get_type() {
+            Some(value) => value,
+            None => &self[type_default_key()],
+        }
+    }
+    #[inline]
+    fn get_default<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<Option<&T>, InitError> {
+        let Some(data) = key.0.data else {
+            return Ok(None);
+        };
+        if let Some(p) = self.cached_default(key.index()) {
+            return unsafe { Ok(Some(&*p.get())) };
+        }
+        self.get_default_with(key, data).map(Some)
+    }
+    /// Kept out of line so that [`get_default`](Self::get_default) stays small for cached and missing values.
+    #[inline(never)]
+    fn get_default_with<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+        data: &'static dyn KeyData<S, T>,
+    ) -> Result<&T, InitError> {
+        let index = key.index();
+        unsafe {
+            if key.0.shared {
+                let p = key.0.shared_value.get(index, &key.1, data)?;
+                Ok(&*p.get())
+            } else if key.0.computed {
+                let p = self.computed_ptr(index, &key.1, data)?;
+                Ok(&*p.get())
+            } else {
+                let p = self.value_ptr(index, 0, Some(&key.1), data, MUT)?;
+                Ok(&*p.get())
+            }
+        }
+    }
+    /// Returns the default value of a static key read directly from a dense `values`.
+    ///
+    /// Only keys that are neither shared nor computed have a slot in `values`, so the flags need not be checked.
+    /// Returns `None` for maps shared between threads, whose `values` may be resized while reading.
+    #[inline]
+    fn cached_default(&self, index: usize) -> Option<RawPtr> {
+        if <S::Lock as MapLock>::SYNC {
+            return None;
+        }
+        let values = unsafe { &*self.values.get() }.as_dense()?;
+        match values.get(index)? {
+            Some(s) if s.generation == 0 => Some(s.ptr),
+            _ => None,
+        }
+    }
+    fn frame(&self, index: usize) -> Option<NonNull<Frame>> {
+        Some(self.ptrs.get(index)?.frame)
+    }
+    /// Returns the entry of `ptrs` if `index` is below `fast_len`.
+    #[inline]
+    fn fast_entry(&self, index: usize) -> Option<&Option<Bound>> {
+        if index < self.fast_len.load(Ordering::Relaxed) {
+            let Some(entries) = self.ptrs.as_dense() else {
+                // SAFETY: `fast_len` is 0 unless `ptrs` is dense.
+                unsafe { unreachable_unchecked() }
+            };
+            // SAFETY: `fast_len` does not exceed the length of `entries`, which never shrinks.
+            Some(unsafe { entries.get_unchecked(index) })
+        } else {
+            None
+        }
+    }
+    /// Returns the value `fast_len` should have.
+    fn fast_len(&self) -> usize {
+        match self.ptrs.as_dense() {
+            Some(entries) if self.tracking.load(Ordering::Relaxed) == 0 => entries.len(),
+            _ => 0,
+        }
+    }
+
+    /// Returns an iterator over the value corresponding to the key and the values shadowed by it.
+    ///
+    /// The iterator yields the current value first, then the values set by outer [`with`](Self::with) calls,
+    /// and finally the default value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 10 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// m.with(&KEY_A, &20, |m| {
+    ///     m.with(&KEY_A, &30, |m| {
+    ///         let values: Vec<_> = m.stack(&KEY_A).copied().collect();
+    ///         assert_eq!(values, [30, 20, 10]);
+    ///     });
+    /// });
+    /// ```
+    pub fn stack<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Stack<'_, S, T, MUT> {
+        let index = key.index();
+        let frame = self.frame(index);
+        self.track(&[(index, frame)]);
+        Stack {
+            map: Some(self),
+            key,
+            frame,
+        }
+    }
+
+    /// Returns where the value corresponding to the key comes from, together with the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if initializing the default value fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::Entry;
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 10 });
+    /// ctxmap::key!(S { KEY_B: u16 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m.get_entry(&KEY_A), Entry::Default(&10));
+    /// assert_eq!(m.get_entry(&KEY_B), Entry::Missing);
+    /// m.with(&KEY_A, &20, |m| {
+    ///     assert_eq!(m.get_entry(&KEY_A), Entry::Bound(&20));
+    /// });
+    /// ```
+    pub fn get_entry<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Entry<'_, T> {
+        unwrap_init(self.try_get_entry(key))
+    }
+    fn try_get_entry<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<Entry<'_, T>, InitError> {
+        let index = key.index();
+        let frame = self.frame(index);
+        self.track(&[(index, frame)]);
+        Ok(if let Some(p) = frame {
+            Entry::Bound(unsafe { p.as_ref().value() })
+        } else if let Some(value) = self.get_default(key)? {
+            Entry::Default(value)
+        } else {
+            Entry::Missing
+        })
+    }
+    fn visible_entry<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Option<(EntryState, &T)> {
+        let index = key.index();
+        if let Some(p) = self.frame(index) {
+            return Some((EntryState::Bound, unsafe { p.as_ref().value() }));
+        }
+        key.0.data?;
+        let p = if key.0.shared {
+            key.0.shared_value.find()?
+        } else {
+            let p = self.find_value(index, 0);
+            p.or_else(|| self.find_computed(index, 0))?
+        };
+        Some((EntryState::Default, unsafe { &*p.get() }))
+    }
+    fn debug_entry<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> DebugEntry<'_, T> {
+        DebugEntry {
+            entry: self.visible_entry(key),
+            has_default: key.0.data.is_some(),
+            debug: (key.0.debug)(),
+            secret: key.0.secret,
+        }
+    }
+
+    /// Returns an iterator over the keys that currently have a value, together with the values.
+    ///
+    /// Default values that have not been initialized yet are not included, and are not initialized by this method.
+    /// Only keys defined with [`key`] macro are included, so values set by [`with_type`](Self::with_type)
+    /// or [`with_dyn`](Self::with_dyn) are not included.
+    /// The keys are in the same order as [`Schema::keys`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::EntryState;
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S {
+    ///     KEY_A: u16 = 10,
+    ///     KEY_B: str,
+    ///     KEY_C: u8 = 5,
+    /// });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m[&KEY_A], 10);
+    /// m.with(&KEY_B, "abc", |m| {
+    ///     let entries: Vec<_> = m.iter().collect();
+    ///     assert_eq!(entries.len(), 2);
+    ///     assert_eq!(entries[0].0.name(), "KEY_A");
+    ///     assert_eq!(entries[0].1, EntryState::Default);
+    ///     assert_eq!(entries[0].2.downcast_ref::<u16>(), Some(&10));
+    ///     assert_eq!(entries[1].0.name(), "KEY_B");
+    ///     assert_eq!(entries[1].1, EntryState::Bound);
+    ///     assert_eq!(entries[1].2.downcast_ref::<str>(), Some("abc"));
+    /// });
+    /// ```
+    pub fn iter(&self) -> Iter<'_, S> {
+        Iter {
+            map: self,
+            entries: key_entries::<S>().iter(),
+        }
+    }
+
+    /// Returns the value corresponding to the key with the name, or `None` if there is no such key or no value.
+    ///
+    /// Keys are looked up in the keys defined with [`key`] macro for the schema.
+    /// Names shared by several keys, for example keys defined in different modules, are ambiguous and not found.
+    /// Like [`get`](Self::get), the default value is initialized if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if initializing the default value fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S {
+    ///     KEY_A: u16 = 10,
+    ///     KEY_B: str,
+    /// });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// let a = m.get_by_name("KEY_A").unwrap();
+    /// assert_eq!(a.downcast_ref::<u16>(), Some(&10));
+    /// assert_eq!(format!("{a:?}"), "10");
+    /// assert!(m.get_by_name("KEY_B").is_none());
+    /// m.with(&KEY_B, "abc", |m| {
+    ///     assert_eq!(m.get_by_name("KEY_B").unwrap().downcast_ref::<str>(), Some("abc"));
+    /// });
+    /// assert!(m.get_by_name("KEY_C").is_none());
+    /// ```
+    pub fn get_by_name(&self, name: &str) -> Option<Value<'_>> {
+        key_entry_by_name::<S>(name)?.get(self)
+    }
+
+    /// Returns `true` if a value corresponding to the key is set by [`with`](Self::with) or similar methods.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 10 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert!(!m.is_overridden(&KEY_A));
+    /// m.with(&KEY_A, &20, |m| {
+    ///     assert!(m.is_overridden(&KEY_A));
+    /// });
+    /// ```
+    pub fn is_overridden<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> bool {
+        let index = key.index();
+        let frame = self.frame(index);
+        self.track(&[(index, frame)]);
+        frame.is_some()
+    }
+
+    /// Returns `true` if the key has a default value.
+    ///
+    /// The default value is not initialized by this method.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 10 });
+    /// ctxmap::key!(S { KEY_B: u16 });
+    ///
+    /// let m = ctxmap::CtxMap::new();
+    /// assert!(m.has_default(&KEY_A));
+    /// assert!(!m.has_default(&KEY_B));
+    /// ```
+    pub fn has_default<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> bool {
+        key.0.data.is_some()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { mut KEY_A: u16 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// assert_eq!(m.get_mut(&KEY_A), None);
+    /// m.with_mut(&KEY_A, &mut 10, |m| {
+    ///     assert_eq!(m.get_mut(&KEY_A), Some(&mut 10));
+    /// });
+    /// assert_eq!(m.get_mut(&KEY_A), None);
+    /// ```
+    pub fn get_mut<T: ?Sized>(&mut self, key: &'static KeyMut<S, T>) -> Option<&mut T> {
+        let index = key.index();
+        unsafe {
+            if let Some(b) = self.ptrs.get(index) {
+                Some(b.value_mut())
+            } else {
+                let data = key.0.data?;
+                let p = unwrap_init(self.value_ptr(index, 0, Some(&key.1), data, true));
+                Some(&mut *p.get::<T>().cast_mut())
+            }
+        }
+    }
+    /// Returns the pointer to the default value, initializing it if needed.
+    ///
+    /// The pointer is derived from a mutable reference if `mutable` is `true`.
+    /// A default value that read keys set by `with` is kept only while they are set, like values of computed keys.
+    fn value_ptr<T: ?Sized>(
+        &self,
+        index: usize,
+        generation: usize,
+        key: Option<&KeyInfo>,
+        data: &dyn KeyData<S, T>,
+        mutable: bool,
+    ) -> Result<RawPtr, InitError> {
+        if let Some(p) = self.find_value(index, generation) {
+            return Ok(p);
+        }
+        if let Some(p) = self.find_computed(index, generation) {
+            return Ok(p);
+        }
+        let (value, deps) = self.collect_deps(|| self.init_value(index, key, data));
+        let value = value?;
+        if deps.iter().any(|d| d.1.is_some()) {
+            let slot = Slot::new(generation, value, data, mutable);
+            return Ok(unsafe { self.push_computed(index, deps, slot) });
+        }
+        self.track(&deps);
+        let mut _stale = None;
+        let _lock = self.lock();
+        let values = unsafe { &mut *self.values.get() };
+        match values.get(index) {
+            Some(s) if s.generation == generation => Ok(s.ptr),
+            _ => {
+                let slot = Slot::new(generation, value, data, mutable);
+                let ptr = slot.ptr;
+                _stale = values.replace(index, Some(slot));
+                Ok(ptr)
+            }
+        }
+    }
+    fn init_value<T: ?Sized>(
+        &self,
+        index: usize,
+        key: Option<&KeyInfo>,
+        data: &dyn KeyData<S, T>,
+    ) -> Result<Box<dyn Any>, InitError> {
+        init_default(self.addr(), index, key, || data.init(self))
+    }
+    unsafe fn computed_ptr<T: ?Sized>(
+        &self,
+        index: usize,
+        key: &KeyInfo,
+        data: &dyn KeyData<S, T>,
+    ) -> Result<RawPtr, InitError> {
+        if let Some(p) = self.find_computed(index, 0) {
+            return Ok(p);
+        }
+        let (value, deps) = self.collect_deps(|| self.init_value(index, Some(key), data));
+        let slot = Slot::new(0, value?, data, false);
+        Ok(self.push_computed(index, deps, slot))
+    }
+    /// Adds a value derived from `deps`, or returns the value added by another thread in the meantime.
+    unsafe fn push_computed(&self, index: usize, deps: Vec<Dep>, slot: Slot) -> RawPtr {
+        let computed = Computed { index, deps, slot };
+        let _lock = self.lock();
+        if let Some(p) = self.find_computed_locked(index, computed.slot.generation) {
+            return p;
+        }
+        let list = &mut *self.computed.get();
+        list.push(computed);
+        let c = list.last().unwrap();
+        self.track(&c.deps);
+        c.slot.ptr
+    }
+    fn find_computed(&self, index: usize, generation: usize) -> Option<RawPtr> {
+        let _lock = self.lock();
+        self.find_computed_locked(index, generation)
+    }
+    fn find_computed_locked(&self, index: usize, generation: usize) -> Option<RawPtr> {
+        let list = unsafe { &*self.computed.get() };
+        let c = list.iter().rev().find(|c| {
+            c.index == index
+                && c.slot.generation == generation
+                && c.deps.iter().all(|&(i, f)| self.frame(i) == f)
+        })?;
+        self.track(&c.deps);
+        Some(c.slot.ptr)
+    }
+    fn collect_deps<U>(&self, f: impl FnOnce() -> U) -> (U, Vec<Dep>) {
+        DEPS.with_borrow_mut(|s| s.push((self.addr(), Vec::new())));
+        {
+            let _lock = self.lock();
+            self.tracking.fetch_add(1, Ordering::Relaxed);
+            self.fast_len.store(0, Ordering::Relaxed);
+        }
+        let _guard = DepsGuard(self);
+        let value = f();
+        let deps = DEPS.with_borrow_mut(|s| std::mem::take(&mut s.last_mut().unwrap().1));
+        (value, deps)
+    }
+    fn track(&self, deps: &[Dep]) {
+        // Only the thread computing a value of this map can see a matching entry in `DEPS`.
+        if self.tracking.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        DEPS.with_borrow_mut(|s| {
+            if let Some((map, list)) = s.last_mut() {
+                if *map == self.addr() {
+                    list.extend_from_slice(deps);
+                }
+            }
+        });
+    }
+    fn addr(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+    fn find_value(&self, index: usize, generation: usize) -> Option<RawPtr> {
+        let _lock = self.lock();
+        let values = unsafe { &*self.values.get() };
+        match values.get(index)? {
+            s if s.generation == generation => Some(s.ptr),
+            _ => None,
+        }
+    }
+    /// Removes the computed values derived from `frames`, whose scope has ended.
+    fn discard_computed(&mut self, frames: &[Frame]) {
+        let computed = self.computed.get_mut();
+        if computed.is_empty() {
+            return;
+        }
+        let frames = frames.as_ptr_range();
+        let is_discarded = |d: &Dep| {
+            d.1.is_some_and(|f| frames.contains(&f.as_ptr().cast_const()))
+        };
+        computed.retain(|c| !c.deps.iter().any(is_discarded));
+    }
+    fn enter_ptr<U>(map: NonNull<Self>, f: impl FnOnce() -> U) -> U {
+        let _guard = CurrentGuard::<S>(S::current().with(|c| c.0.replace(Some(map))));
+        f()
+    }
+    fn lock(&self) -> <S::Lock as MapLock>::Guard<'_> {
+        self.lock.lock()
+    }
+}
+
+impl<S: SyncSchema> CtxMap<S> {
+    /// Creates a scope for spawning threads that share `self`.
+    ///
+    /// Each thread spawned by [`CtxScope::spawn`] receives a reference to `self`,
+    /// and `self` is also the current map of the thread (see [`current`]).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(sync S);
+    /// ctxmap::key!(S { KEY_A: u16 = 20 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// m.with(&KEY_A, &30, |m| {
+    ///     m.scope(|s, _| {
+    ///         let h0 = s.spawn(|m| m[&KEY_A]);
+    ///         let h1 = s.spawn(|_| ctxmap::current(|m| m[&KEY_A]));
+    ///         assert_eq!(h0.join().unwrap(), 30);
+    ///         assert_eq!(h1.join().unwrap(), 30);
+    ///     });
+    /// });
+    /// ```
+    pub fn scope<'env, U>(
+        &'env self,
+        f: impl for<'scope> FnOnce(&CtxScope<'scope, 'env, S>, &'env CtxMap<S>) -> U,
+    ) -> U {
+        thread::scope(|scope| f(&CtxScope { scope, map: self }, self))
+    }
+}
+
+impl<S: Schema> Default for CtxMap<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<S: Schema> fmt::Debug for CtxMap<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_map(self, f)
+    }
+}
+impl<S, T, const MUT: bool> Index<&'static Key<S, T, MUT>> for CtxMap<S>
+where
+    S: Schema,
+    T: ?Sized,
+{
+    type Output = T;
+
+    #[inline]
+    fn index(&self, index: &'static Key<S, T, MUT>) -> &Self::Output {
+        self.get(index)
+            .unwrap_or_else(|| panic!("{}", MissingKey(&index.1)))
+    }
+}
+impl<S, T> IndexMut<&'static KeyMut<S, T>> for CtxMap<S>
+where
+    S: Schema,
+    T: ?Sized,
+{
+    fn index_mut(&mut self, index: &'static KeyMut<S, T>) -> &mut Self::Output {
+        self.get_mut(index)
+            .unwrap_or_else(|| panic!("{}", MissingKey(&index.1)))
+    }
+}
+
+impl<S: Schema> UnwindSafe for CtxMap<S> {}
+impl<S: Schema> RefUnwindSafe for CtxMap<S> {}
+unsafe impl<S: SyncSchema> Send for CtxMap<S> {}
+unsafe impl<S: SyncSchema> Sync for CtxMap<S> {}
+
+/// Mutable reference to [`CtxMap`] where the value has changed.
+///
+/// Use `CtxMapView` instead of `&mut CtxMap` because `&mut CtxMap`,
+/// whose value has been changed, will be broken if [`std::mem::swap`] is used.
+pub struct CtxMapView<'a, S: Schema>(NonNull<CtxMap<S>>, PhantomData<&'a mut CtxMap<S>>);
+
+impl<S: Schema> CtxMapView<'_, S> {
+    /// Sets a value to `CtxMap` only while `f` is being called.
+    ///
+    /// See [`CtxMap::with`] for more details.
+    pub fn with<T: ?Sized, U>(
+        &mut self,
+        key: &'static Key<S, T>,
+        value: &T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        self.with_impl(key, value, false, f)
+    }
+
+    /// Sets a mutable value to `CtxMap` only while `f` is being called.
+    ///
+    /// See [`CtxMap::with_mut`] for more details.
+    pub fn with_mut<T: ?Sized, U, const MUT: bool>(
+        &mut self,
+        key: &'static Key<S, T, MUT>,
+        value: &mut T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let ptr: *mut T = value;
+        self.with_impl(key, ptr, true, f)
+    }
+
+    /// Appends an item to the slice corresponding to the key only while `f` is being called.
+    ///
+    /// See [`CtxMap::with_append`] for more details.
+    pub fn with_append<T: Clone + 'static, U>(
+        &mut self,
+        key: &'static Key<S, [T]>,
+        item: T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let mut values = self.get(key).unwrap_or(&[]).to_vec();
+        values.push(item);
+        self.with(key, &values[..], f)
+    }
+
+    /// Sets a value keyed by its type only while `f` is being called.
+    ///
+    /// See [`CtxMap::with_type`] for more details.
+    pub fn with_type<T: ?Sized + 'static, U>(
+        &mut self,
+        value: &T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U
+    where
+        S: ValueBound<T, false>,
+    {
+        self.with(type_key(), value, f)
+    }
+
+    /// Sets a value corresponding to the runtime key only while `f` is being called.
+    ///
+    /// See [`CtxMap::with_dyn`] for more details.
+    pub fn with_dyn<T: ?Sized + 'static, U>(
+        &mut self,
+        key: &DynKey<S, T>,
+        value: &T,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        self.with_raws([(key.0.index, RawPtr::new::<T>(value), false)], f)
+    }
+
+    fn with_impl<T: ?Sized, U, const MUT: bool>(
+        &mut self,
+        key: &'static Key<S, T, MUT>,
+        ptr: *const T,
+        mutable: bool,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        self.with_raws([(key.index(), RawPtr::new(ptr), mutable)], f)
+    }
+    /// Sets multiple values to `CtxMap` only while `f` is being called.
+    ///
+    /// See [`CtxMap::with_all`] for more details.
+    pub fn with_all<U>(
+        &mut self,
+        bindings: impl Bindings<S>,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        bindings.with_bindings(self, f)
+    }
+    fn with_raws<U, const N: usize>(
+        &mut self,
+        entries: [(usize, RawPtr, bool); N],
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let frames = entries.map(|(index, ptr, mutable)| Frame::new(index, ptr, mutable));
+        self.with_frames(&frames, true, f)
+    }
+    /// Sets `frames` only while `f` is being called.
+    ///
+    /// If `discard` is `false`, computed values derived from `frames` are kept after `f` returns,
+    /// so that the same frames can be set again later. See [`AsyncFrame`].
+    fn with_frames<U>(
+        &mut self,
+        frames: &[Frame],
+        discard: bool,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let map = self.0;
+        let m = self.map_mut();
+        let fast_len = *m.fast_len.get_mut();
+        let mut grown = false;
+        for frame in frames {
+            let prev = m.ptrs.replace(frame.index, Some(Bound::new(frame)));
+            frame.prev.set(prev.map(|b| b.frame));
+            grown |= frame.index >= fast_len;
+        }
+        if grown {
+            *m.fast_len.get_mut() = m.fast_len();
+        }
+        let _restore = Restore {
+            map,
+            frames,
+            discard,
+        };
+        f(self)
+    }
+
+    /// Returns an iterator over the value corresponding to the key and the values shadowed by it.
+    ///
+    /// See [`CtxMap::stack`] for more details.
+    pub fn stack<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Stack<'_, S, T, MUT> {
+        self.map().stack(key)
+    }
+
+    /// Returns where the value corresponding to the key comes from, together with the value.
+    ///
+    /// See [`CtxMap::get_entry`] for more details.
+    pub fn get_entry<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Entry<'_, T> {
+        self.map().get_entry(key)
+    }
+
+    /// Returns an iterator over the keys that currently have a value, together with the values.
+    ///
+    /// See [`CtxMap::iter`] for more details.
+    pub fn iter(&self) -> Iter<'_, S> {
+        self.map().iter()
+    }
+
+    /// Returns `true` if a value corresponding to the key is set by [`with`](Self::with) or similar methods.
+    ///
+    /// See [`CtxMap::is_overridden`] for more details.
+    pub fn is_overridden<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> bool {
+        self.map().is_overridden(key)
+    }
+
+    /// Returns `true` if the key has a default value.
+    ///
+    /// See [`CtxMap::has_default`] for more details.
+    pub fn has_default<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> bool {
+        self.map().has_default(key)
+    }
+
+    /// Returns a snapshot of the values currently set.
+    ///
+    /// See [`CtxMap::snapshot`] for more details.
+    pub fn snapshot(&self) -> CtxSnapshot<S> {
+        self.map().snapshot()
+    }
+
+    /// Sets the values captured in `snapshot` only while `f` is being called.
+    ///
+    /// See [`CtxMap::snapshot`] for more details.
+    pub fn with_snapshot<U>(
+        &mut self,
+        snapshot: &CtxSnapshot<S>,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let frames: Vec<_> = snapshot
+            .entries
+            .iter()
+            .map(|(index, value)| Frame::new(*index, value.ptr(), false))
+            .collect();
+        self.with_frames(&frames, true, f)
+    }
+
+    /// Sets a value to `CtxMap` only while the future returned by `f` is being polled.
+    ///
+    /// See [`CtxMap::with_async`] for more details.
+    pub async fn with_async<T: ?Sized, U>(
+        &mut self,
+        key: &'static Key<S, T>,
+        value: &T,
+        f: impl AsyncFnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        let ptr: *const T = value;
+        let map = self.0;
+        let future = f(self);
+        WithAsync {
+            future,
+            frame: AsyncFrame {
+                map,
+                frame: Frame::new(key.index(), RawPtr::new(ptr), false),
+            },
+            _pinned: PhantomPinned,
+        }
+        .await
+    }
+
+    /// Returns a future that sets a value to `CtxMap` only while `future` is being polled.
+    ///
+    /// See [`CtxMap::bind_future`] for more details.
+    pub fn bind_future<'a, T: ?Sized, F: Future>(
+        &'a mut self,
+        key: &'static Key<S, T>,
+        value: &'a T,
+        future: F,
+    ) -> WithCtx<'a, S, F> {
+        let ptr: *const T = value;
+        WithCtx {
+            map: self.view(),
+            binding: Some((key.index(), RawPtr::new(ptr))),
+            future,
+        }
+    }
+
+    /// Makes `CtxMap` the current map of the thread only while `f` is being called.
+    ///
+    /// See [`CtxMap::enter`] for more details.
+    pub fn enter<U>(&mut self, f: impl FnOnce() -> U) -> U {
+        CtxMap::enter_ptr(self.0, f)
+    }
+
+    /// Return `CtxMapView` with modified lifetime.
+    pub fn view(&mut self) -> CtxMapView<'_, S> {
+        CtxMapView(self.0, PhantomData)
+    }
+
+    fn map(&self) -> &CtxMap<S> {
+        unsafe { self.0.as_ref() }
+    }
+    fn map_mut(&mut self) -> &mut CtxMap<S> {
+        unsafe { self.0.as_mut() }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// See [`CtxMap::get`] for more details.
+    #[inline]
+    pub fn get<T: ?Sized, const MUT: bool>(&self, key: &'static Key<S, T, MUT>) -> Option<&T> {
+        self.map().get(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, or an error if initializing the default value fails.
+    ///
+    /// See [`CtxMap::try_get`] for more details.
+    pub fn try_get<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<Option<&T>, InitError> {
+        self.map().try_get(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, or an error describing the key if there is no value.
+    ///
+    /// See [`CtxMap::require`] for more details.
+    pub fn require<T: ?Sized, const MUT: bool>(
+        &self,
+        key: &'static Key<S, T, MUT>,
+    ) -> Result<&T, MissingKey> {
+        self.map().require(key)
+    }
+
+    /// Returns a reference to the value corresponding to the runtime key.
+    ///
+    /// See [`CtxMap::get_dyn`] for more details.
+    pub fn get_dyn<'a, T: ?Sized + 'static>(&'a self, key: &'a DynKey<S, T>) -> Option<&'a T> {
+        self.map().get_dyn(key)
+    }
+
+    /// Returns the value corresponding to the key with the name.
+    ///
+    /// See [`CtxMap::get_by_name`] for more details.
+    pub fn get_by_name(&self, name: &str) -> Option<Value<'_>> {
+        self.map().get_by_name(name)
+    }
+
+    /// Returns a reference to the value keyed by its type.
+    ///
+    /// See [`CtxMap::get_type`] for more details.
+    pub fn get_type<T: ?Sized + 'static>(&self) -> Option<&T> {
+        self.map().get_type()
+    }
+
+    /// Returns a reference to the value keyed by its type, or the default value of the type if there is no value.
+    ///
+    /// See [`CtxMap::get_type_or_default`] for more details.
+    pub fn get_type_or_default<T: Default + 'static>(&self) -> &T
+    where
+        S: ValueBound<T, false> + InitBound<T>,
+    {
+        self.map().get_type_or_default()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// See [`CtxMap::get_mut`] for more details.
+    pub fn get_mut<T: ?Sized>(&mut self, key: &'static KeyMut<S, T>) -> Option<&mut T> {
+        self.map_mut().get_mut(key)
+    }
+}
+
+impl<S: SyncSchema> CtxMapView<'_, S> {
+    /// Creates a scope for spawning threads that share `CtxMap`.
+    ///
+    /// See [`CtxMap::scope`] for more details.
+    pub fn scope<'env, U>(
+        &'env self,
+        f: impl for<'scope> FnOnce(&CtxScope<'scope, 'env, S>, &'env CtxMap<S>) -> U,
+    ) -> U {
+        self.map().scope(f)
+    }
+}
+
+impl<S: Schema> UnwindSafe for CtxMapView<'_, S> {}
+impl<S: Schema> RefUnwindSafe for CtxMapView<'_, S> {}
+unsafe impl<S: SyncSchema> Send for CtxMapView<'_, S> {}
+unsafe impl<S: SyncSchema> Sync for CtxMapView<'_, S> {}
+
+impl<S: Schema> fmt::Debug for CtxMapView<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.map().fmt(f)
+    }
+}
+
+impl<S, T, const MUT: bool> Index<&'static Key<S, T, MUT>> for CtxMapView<'_, S>
+where
+    S: Schema,
+    T: ?Sized,
+{
+    type Output = T;
+
+    #[inline]
+    fn index(&self, index: &'static Key<S, T, MUT>) -> &Self::Output {
+        &self.map()[index]
+    }
+}
+impl<S, T> IndexMut<&'static KeyMut<S, T>> for CtxMapView<'_, S>
+where
+    S: Schema,
+    T: ?Sized,
+{
+    fn index_mut(&mut self, index: &'static KeyMut<S, T>) -> &mut Self::Output {
+        &mut self.map_mut()[index]
+    }
+}
+
+/// A pair of a key and a reference to a value for [`CtxMap::with_all`].
+///
+/// Implemented for `(&'static Key<S, T>, &T)` and `(&'static Key<S, T, MUT>, &mut T)`.
+pub trait Binding<S: Schema>: binding::Sealed {
+    /// Returns the index of the key, the pointer to the value, and whether the value is mutable.
+    #[doc(hidden)]
+    fn into_raw(self) -> (usize, RawPtr, bool);
+}
+
+impl<S: Schema, T: ?Sized> Binding<S> for (&'static Key<S, T>, &T) {
+    fn into_raw(self) -> (usize, RawPtr, bool) {
+        (self.0.index(), RawPtr::new::<T>(self.1), false)
+    }
+}
+impl<S: Schema, T: ?Sized, const MUT: bool> Binding<S> for (&'static Key<S, T, MUT>, &mut T) {
+    fn into_raw(self) -> (usize, RawPtr, bool) {
+        let ptr: *mut T = self.1;
+        (self.0.index(), RawPtr::new(ptr), true)
+    }
+}
+
+/// Values to be set at once by [`CtxMap::with_all`].
+///
+/// Implemented for [`Binding`] and tuples of `Binding`.
+pub trait Bindings<S: Schema>: binding::Sealed {
+    #[doc(hidden)]
+    fn with_bindings<U>(
+        self,
+        map: &mut CtxMapView<S>,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U;
+}
+
+impl<S: Schema, T: ?Sized> Bindings<S> for (&'static Key<S, T>, &T) {
+    fn with_bindings<U>(
+        self,
+        map: &mut CtxMapView<S>,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        (self,).with_bindings(map, f)
+    }
+}
+impl<S: Schema, T: ?Sized, const MUT: bool> Bindings<S> for (&'static Key<S, T, MUT>, &mut T) {
+    fn with_bindings<U>(
+        self,
+        map: &mut CtxMapView<S>,
+        f: impl FnOnce(&mut CtxMapView<S>) -> U,
+    ) -> U {
+        (self,).with_bindings(map, f)
+    }
+}
+
+macro_rules! impl_bindings {
+    ($($b:ident $i:tt),*) => {
+        impl<S: Schema, $($b: Binding<S>),*> Bindings<S> for ($($b,)*) {
+            fn with_bindings<U>(
+                self,
+                map: &mut CtxMapView<S>,
+                f: impl FnOnce(&mut CtxMapView<S>) -> U,
+            ) -> U {
+                map.with_raws([$(self.$i.into_raw()),*], f)
+            }
+        }
+        impl<$($b: binding::Sealed),*> binding::Sealed for ($($b,)*) {}
+    };
+}
+
+impl_bindings!(B0 0);
+impl_bindings!(B0 0, B1 1);
+impl_bindings!(B0 0, B1 1, B2 2);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6, B7 7);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6, B7 7, B8 8);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6, B7 7, B8 8, B9 9);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6, B7 7, B8 8, B9 9, B10 10);
+impl_bindings!(B0 0, B1 1, B2 2, B3 3, B4 4, B5 5, B6 6, B7 7, B8 8, B9 9, B10 10, B11 11);
+
+mod binding {
+    use crate::{Key, Schema};
+
+    pub trait Sealed {}
+
+    impl<S: Schema, T: ?Sized, const MUT: bool> Sealed for (&'static Key<S, T, MUT>, &T) {}
+    impl<S: Schema, T: ?Sized, const MUT: bool> Sealed for (&'static Key<S, T, MUT>, &mut T) {}
+}
+
+/// Values captured from [`CtxMap`].
+///
+/// Created by [`CtxMap::snapshot`] and [`CtxMapView::snapshot`].
+pub struct CtxSnapshot<S: Schema> {
+    schema: PhantomData<S>,
+    entries: Vec<(usize, Box<dyn SnapshotValue>)>,
+}
+
+unsafe impl<S: SyncSchema> Send for CtxSnapshot<S> {}
+unsafe impl<S: SyncSchema> Sync for CtxSnapshot<S> {}
+
+impl<S: Schema> CtxSnapshot<S> {
+    /// Returns `true` if the snapshot contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+trait SnapshotValue {
+    fn ptr(&self) -> RawPtr;
+}
+
+type SnapshotFn = unsafe fn(&Frame) -> Box<dyn SnapshotValue>;
+
+struct Cloned<T>(*const T);
+
+impl<T: Clone + 'static> Cloned<T> {
+    unsafe fn snapshot(frame: &Frame) -> Box<dyn SnapshotValue> {
+        let value = Box::new(frame.value::<T>().clone());
+        Box::new(Cloned(Box::into_raw(value)))
+    }
+}
+impl<T: 'static> SnapshotValue for Cloned<T> {
+    fn ptr(&self) -> RawPtr {
+        RawPtr::new(self.0)
+    }
+}
+impl<T> Drop for Cloned<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.0.cast_mut()) });
+    }
+}
+
+/// A scope for spawning threads that share [`CtxMap`].
+///
+/// Created by [`CtxMap::scope`] and [`CtxMapView::scope`].
+pub struct CtxScope<'scope, 'env: 'scope, S: SyncSchema> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    map: &'env CtxMap<S>,
+}
+
+impl<'scope, 'env, S: SyncSchema> CtxScope<'scope, 'env, S> {
+    /// Spawns a scoped thread that receives a reference to the shared `CtxMap`.
+    ///
+    /// While `f` is being called, the shared `CtxMap` is also the current map of the spawned thread.
+    pub fn spawn<T, F>(&self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce(&'env CtxMap<S>) -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let map = self.map;
+        self.scope
+            .spawn(move || CtxMap::enter_ptr(NonNull::from(map), || f(map)))
+    }
+}
+
+/// A future that makes [`CtxMap`] the current map of the thread only while the inner future is being polled.
+///
+/// Created by [`CtxMap::bind_future`], [`CtxMapView::bind_future`] and [`FutureExt::with_ctx`].
+pub struct WithCtx<'a, S: Schema, F> {
+    map: CtxMapView<'a, S>,
+    binding: Option<(usize, RawPtr)>,
+    future: F,
+}
+
+unsafe impl<S: SyncSchema, F: Send> Send for WithCtx<'_, S, F> {}
+
+impl<S: Schema, F: Future> Future for WithCtx<'_, S, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Some((index, ptr)) = this.binding {
+            this.map
+                .with_raws([(index, ptr, false)], |m| m.enter(|| future.poll(cx)))
+        } else {
+            this.map.enter(|| future.poll(cx))
+        }
+    }
+}
+
+/// A future that sets the binding of [`CtxMapView::with_async`] while the inner future is being polled.
+///
+/// Unlike [`WithCtx`], the inner future can borrow values from the map across `.await`,
+/// so the frame is kept at the same address and computed values derived from it are kept between polls.
+/// The map is not made the current map, because the inner future already has a mutable reference to it.
+struct WithAsync<S: Schema, F> {
+    // Dropped before `frame`, because the inner future may borrow computed values discarded by `frame`.
+    future: F,
+    frame: AsyncFrame<S>,
+    _pinned: PhantomPinned,
+}
+
+unsafe impl<S: SyncSchema, F: Send> Send for WithAsync<S, F> {}
+
+impl<S: Schema, F: Future> Future for WithAsync<S, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let frame = &this.frame;
+        CtxMapView(frame.map, PhantomData)
+            .with_frames(slice::from_ref(&frame.frame), false, |_| future.poll(cx))
+    }
+}
+
+/// The frame of [`WithAsync`]. Discards computed values derived from the frame when dropped.
+struct AsyncFrame<S: Schema> {
+    map: NonNull<CtxMap<S>>,
+    frame: Frame,
+}
+
+impl<S: Schema> Drop for AsyncFrame<S> {
+    fn drop(&mut self) {
+        unsafe { self.map.as_mut() }.discard_computed(slice::from_ref(&self.frame));
+    }
+}
+
+/// Extension methods for [`Future`].
+pub trait FutureExt: Future + Sized {
+    /// Makes `map` the current map of the thread only while `self` is being polled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::FutureExt;
+    /// use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 = 20 });
+    ///
+    /// let mut m = ctxmap::CtxMap::new();
+    /// m.with(&KEY_A, &30, |m| {
+    ///     let f = async { ctxmap::current(|m| m[&KEY_A]) }.with_ctx(m.view());
+    ///     let mut cx = Context::from_waker(Waker::noop());
+    ///     assert_eq!(pin!(f).poll(&mut cx), Poll::Ready(30));
+    /// });
+    /// ```
+    fn with_ctx<S: Schema>(self, map: CtxMapView<S>) -> WithCtx<S, Self> {
+        WithCtx {
+            map,
+            binding: None,
+            future: self,
+        }
+    }
+}
+impl<F: Future> FutureExt for F {}
+
+/// Calls `f` with the current map of the thread.
+///
+/// The current map is the map passed to the innermost [`CtxMap::enter`] or [`CtxMapView::enter`] call.
+/// If there is no current map, `f` is called with an empty map of the thread,
+/// which keeps the default values initialized in it for later calls.
+///
+/// # Example
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S { KEY_A: u16 });
+///
+/// let mut m = ctxmap::CtxMap::new();
+/// assert_eq!(ctxmap::current(|m| m.get(&KEY_A).copied()), None);
+/// m.with(&KEY_A, &10, |m| {
+///     m.enter(|| {
+///         assert_eq!(ctxmap::current(|m| m.get(&KEY_A).copied()), Some(10));
+///     });
+/// });
+/// ```
+pub fn current<S: Schema, U>(f: impl FnOnce(&CtxMap<S>) -> U) -> U {
+    S::current().with(|c| {
+        if let Some(p) = c.0.get() {
+            f(unsafe { p.as_ref() })
+        } else {
+            f(c.1.get_or_init(CtxMap::new))
+        }
+    })
+}
+
+/// A value set by `with`, linked to the value it shadows.
+///
+/// `mutable` is `true` if `ptr` was created from a mutable reference.
+/// `prev` is set each time the frame is set to the map.
+struct Frame {
+    index: usize,
+    ptr: RawPtr,
+    mutable: bool,
+    prev: Cell<Option<NonNull<Frame>>>,
+}
+
+impl Frame {
+    fn new(index: usize, ptr: RawPtr, mutable: bool) -> Self {
+        Self {
+            index,
+            ptr,
+            mutable,
+            prev: Cell::new(None),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `T` must be the value type of the key of the frame.
+    #[inline]
+    unsafe fn value<'a, T: ?Sized>(&self) -> &'a T {
+        &*self.ptr.get()
+    }
+}
+
+/// An entry of the map for a key set by `with`.
+///
+/// Only the pointer to `frame` is stored, so that setting and restoring an entry writes a single word.
+#[derive(Clone, Copy)]
+struct Bound {
+    frame: NonNull<Frame>,
+}
+
+impl Bound {
+    fn new(frame: &Frame) -> Self {
+        Self {
+            frame: NonNull::from(frame),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `T` must be the value type of the key of the frame.
+    #[inline]
+    unsafe fn value<'a, T: ?Sized>(&self) -> &'a T {
+        self.frame.as_ref().value()
+    }
+
+    /// # Safety
+    ///
+    /// `T` must be the value type of the key of the frame, and the value must not be borrowed.
+    unsafe fn value_mut<'a, T: ?Sized>(&self) -> &'a mut T {
+        let frame = self.frame.as_ref();
+        debug_assert!(frame.mutable);
+        &mut *frame.ptr.get::<T>().cast_mut()
+    }
+}
+
+/// A key and the binding of it that a computed value was derived from.
+type Dep = (usize, Option<NonNull<Frame>>);
+
+/// A value of a computed key, or a default value that read keys set by `with`, valid while the bindings in `deps` are unchanged.
+///
+/// Removed when the scope of a binding in `deps` ends.
+struct Computed {
+    index: usize,
+    deps: Vec<Dep>,
+    slot: Slot,
+}
+
+thread_local! {
+    static DEPS: RefCell<Vec<(*const (), Vec<Dep>)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct DepsGuard<'a, S: Schema>(&'a CtxMap<S>);
+
+impl<S: Schema> Drop for DepsGuard<'_, S> {
+    fn drop(&mut self) {
+        let map = self.0;
+        {
+            let _lock = map.lock();
+            map.tracking.fetch_sub(1, Ordering::Relaxed);
+            map.fast_len.store(map.fast_len(), Ordering::Relaxed);
+        }
+        DEPS.with_borrow_mut(|s| s.pop());
+    }
+}
+
+struct Restore<'a, S: Schema> {
+    map: NonNull<CtxMap<S>>,
+    frames: &'a [Frame],
+    discard: bool,
+}
+
+impl<S: Schema> Drop for Restore<'_, S> {
+    fn drop(&mut self) {
+        let map = unsafe { self.map.as_mut() };
+        for frame in self.frames.iter().rev() {
+            let prev = frame.prev.get().map(|frame| Bound { frame });
+            map.ptrs.replace(frame.index, prev);
+        }
+        if self.discard {
+            map.discard_computed(self.frames);
+        }
+    }
+}
+
+/// The value corresponding to a key and where it comes from.
+///
+/// Returned by [`CtxMap::get_entry`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Entry<'a, T: ?Sized> {
+    /// The value is set by [`CtxMap::with`] or similar methods.
+    Bound(&'a T),
+    /// The value is the default value of the key.
+    Default(&'a T),
+    /// The value is not set and the key has no default value.
+    Missing,
+}
+
+impl<'a, T: ?Sized> Entry<'a, T> {
+    /// Returns the value, or `None` if it is [`Missing`](Self::Missing).
+    pub fn value(&self) -> Option<&'a T> {
+        match *self {
+            Entry::Bound(value) | Entry::Default(value) => Some(value),
+            Entry::Missing => None,
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for Entry<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T: ?Sized> Copy for Entry<'_, T> {}
+
+struct DebugEntry<'a, T: ?Sized> {
+    entry: Option<(EntryState, &'a T)>,
+    has_default: bool,
+    debug: Option<DebugFn<T>>,
+    secret: bool,
+}
+
+impl<T: ?Sized> fmt::Debug for DebugEntry<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, value) = match self.entry {
+            Some((EntryState::Bound, value)) => ("Bound", value),
+            Some((EntryState::Default, value)) => ("Default", value),
+            None if self.has_default => return f.write_str("Uninitialized"),
+            None => return f.write_str("Missing"),
+        };
+        let value = FromFn(|f: &mut fmt::Formatter| fmt_value(value, self.debug, self.secret, f));
+        f.debug_tuple(name).field(&value).finish()
+    }
+}
+
+/// Implements `Debug` and `Display` with a closure.
+struct FromFn<F>(F);
+
+impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Debug for FromFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Display for FromFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+fn fmt_value<T: ?Sized>(
+    value: &T,
+    debug: Option<DebugFn<T>>,
+    secret: bool,
+    f: &mut fmt::Formatter,
+) -> fmt::Result {
+    match debug {
+        _ if secret => f.write_str("<redacted>"),
+        Some(debug) => debug(value, f),
+        None => f.write_str(".."),
+    }
+}
+
+/// Where the value yielded by [`CtxMap::iter`] comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryState {
+    /// The value is set by [`CtxMap::with`] or similar methods.
+    Bound,
+    /// The value is the default value of the key.
+    Default,
+}
+
+/// A value yielded by [`CtxMap::iter`] or returned by [`CtxMap::get_by_name`].
+///
+/// The `Debug` implementation prints the value in the same way as the `Debug` implementation of [`CtxMap`].
+pub struct Value<'a> {
+    ptr: RawPtr,
+    type_id: TypeId,
+    key: ErasedKey,
+    fmt: FmtFn,
+    display: Option<FmtFn>,
+    _marker: PhantomData<&'a ()>,
+}
+type FmtFn = fn(ErasedKey, RawPtr, &mut fmt::Formatter) -> fmt::Result;
+
+impl<'a> Value<'a> {
+    /// Returns a reference to the value if the value type of the key is `T`.
+    pub fn downcast_ref<T: ?Sized + 'static>(&self) -> Option<&'a T> {
+        if self.type_id != TypeId::of::<T>() {
+            return None;
+        }
+        // SAFETY: `ptr` was created from `&'a T` with the same `T`.
+        Some(unsafe { &*self.ptr.get::<T>() })
+    }
+
+    /// Returns the value formatted with [`Display`](fmt::Display) if the value type of the key implements it.
+    ///
+    /// Values of keys declared with `secret` are printed as `<redacted>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S {
+    ///     KEY_A: u16 = 10,
+    ///     KEY_B: Vec<u8> = vec![],
+    /// });
+    ///
+    /// let m = ctxmap::CtxMap::<S>::new();
+    /// let a = m.get_by_name("KEY_A").unwrap();
+    /// assert_eq!(a.display().unwrap().to_string(), "10");
+    /// assert!(m.get_by_name("KEY_B").unwrap().display().is_none());
+    /// ```
+    pub fn display(&self) -> Option<impl fmt::Display + '_> {
+        let display = self.display?;
+        Some(FromFn(move |f: &mut fmt::Formatter| {
+            display(self.key, self.ptr, f)
+        }))
+    }
+}
+
+impl fmt::Debug for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.fmt)(self.key, self.ptr, f)
+    }
+}
+
+/// An iterator over the keys that have a value and the values.
+///
+/// Created by [`CtxMap::iter`] and [`CtxMapView::iter`].
+pub struct Iter<'a, S: Schema> {
+    map: &'a CtxMap<S>,
+    entries: slice::Iter<'static, &'static KeyEntry>,
+}
+
+impl<'a, S: Schema> Iterator for Iter<'a, S> {
+    type Item = (&'static KeyInfo, EntryState, Value<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let e = self.entries.next()?;
+            if let Some((state, value)) = e.value(self.map) {
+                return Some((e.info(), state, value));
+            }
+        }
+    }
+}
+
+/// An iterator over the value corresponding to a key and the values shadowed by it.
+///
+/// Created by [`CtxMap::stack`] and [`CtxMapView::stack`].
+pub struct Stack<'a, S: Schema, T: ?Sized + 'static, const MUT: bool> {
+    map: Option<&'a CtxMap<S>>,
+    key: &'static Key<S, T, MUT>,
+    frame: Option<NonNull<Frame>>,
+}
+
+impl<'a, S: Schema, T: ?Sized + 'static, const MUT: bool> Iterator for Stack<'a, S, T, MUT> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = self.map?;
+        if let Some(frame) = self.frame {
+            let frame = unsafe { frame.as_ref() };
+            self.frame = frame.prev.get();
+            Some(unsafe { frame.value() })
+        } else {
+            self.map = None;
+            unwrap_init(map.get_default(self.key))
+        }
+    }
+}
+
+struct CurrentGuard<S: Schema>(Option<NonNull<CtxMap<S>>>);
+
+impl<S: Schema> Drop for CurrentGuard<S> {
+    fn drop(&mut self) {
+        S::current().with(|c| c.0.set(self.0));
+    }
 }
 
-/// Key collection for [`CtxMap`].
+thread_local! {
+    static INITIALIZING: RefCell<Vec<(*const (), usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks the default value of a key as being initialized to detect cyclic defaults.
+struct InitGuard;
+
+impl InitGuard {
+    fn new(map: *const (), index: usize, key: Option<&KeyInfo>) -> Result<Self, InitError> {
+        INITIALIZING.with_borrow_mut(|s| {
+            if s.contains(&(map, index)) {
+                return Err(InitError::new(match key {
+                    Some(key) => format!("cycle detected at key {key}"),
+                    None => "cycle detected at a runtime key".to_string(),
+                }));
+            }
+            s.push((map, index));
+            Ok(InitGuard)
+        })
+    }
+}
+
+impl Drop for InitGuard {
+    fn drop(&mut self) {
+        INITIALIZING.with_borrow_mut(|s| s.pop());
+    }
+}
+
+/// An error that occurred while initializing the default value of a key.
 ///
-/// Use [`schema`] macro to define a type that implement `Schema`.
-pub trait Schema: 'static + Sized {
-    fn data() -> &'static SchemaData;
+/// Returned by [`CtxMap::try_get`] for keys defined with `try`.
+#[derive(Debug)]
+pub struct InitError(Box<dyn Error + Send + Sync>);
+
+impl InitError {
+    /// Wraps `e`, or returns it as is if it is an `InitError` of another key read with `?`.
+    fn new(e: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        match e.into().downcast::<InitError>() {
+            Ok(e) => *e,
+            Err(e) => Self(e),
+        }
+    }
 }
 
-struct KeyDataValue<Init, ToRef, ToMut> {
-    init: Init,
-    to_ref: ToRef,
-    to_mut: ToMut,
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to initialize the default value: {}", self.0)
+    }
 }
 
-impl<Init, ToRef, ToMut, V, T> KeyData<T> for KeyDataValue<Init, ToRef, ToMut>
-where
-    Init: Send + Sync + Fn() -> V,
-    ToRef: Send + Sync + Fn(&V) -> &T,
-    ToMut: Send + Sync + Fn(&mut V) -> &mut T,
-    V: 'static,
-    T: ?Sized,
-{
-    fn get<'a>(&self, value: &'a dyn Any) -> &'a T {
-        (self.to_ref)(<dyn Any>::downcast_ref::<V>(value).unwrap())
+impl Error for InitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+/// Calls `init` to create the default value of a key of `map`.
+///
+/// Errors of default values read by `init` are returned only if `init` reads them with [`CtxMap::try_get`] and `?`.
+fn init_default(
+    map: *const (),
+    index: usize,
+    key: Option<&KeyInfo>,
+    init: impl FnOnce() -> Result<Box<dyn Any>, InitError>,
+) -> Result<Box<dyn Any>, InitError> {
+    let _guard = InitGuard::new(map, index, key)?;
+    init()
+}
+
+fn unwrap_init<T>(r: Result<T, InitError>) -> T {
+    r.unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// A key for [`CtxMap`].
+///
+/// Use [`key`] macro to create `Key`.
+pub struct Key<S: Schema, T: ?Sized + 'static, const MUT: bool = false>(RawKey<S, T, MUT>, KeyInfo);
+pub type KeyMut<S, T> = Key<S, T, true>;
+
+impl<S: Schema, T: ?Sized + 'static, const MUT: bool> Key<S, T, MUT> {
+    /// Returns the information about the key captured by [`key`] macro.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S { KEY_A: u16 });
+    ///
+    /// assert_eq!(KEY_A.info().name(), "KEY_A");
+    /// assert_eq!(KEY_A.info().schema(), "S");
+    /// assert_eq!(KEY_A.info().type_name(), "u16");
+    /// ```
+    pub fn info(&self) -> &KeyInfo {
+        &self.1
+    }
+
+    /// Returns the index of the key, which is assigned at the first use of a key of the schema.
+    #[inline]
+    fn index(&'static self) -> usize {
+        match self.0.index.load(Ordering::Relaxed) {
+            usize::MAX => static_key_index(self),
+            index => index,
+        }
+    }
+}
+
+/// A key for [`CtxMap`] created at runtime.
+///
+/// Unlike [`Key`], `DynKey` does not need to be a `static`, so it can be created by plugins loaded after startup.
+/// When the last clone of the key is dropped, its slot is reused by keys created later.
+/// Values of the dropped key left in a map are never seen through the new key.
+pub struct DynKey<S: Schema, T: ?Sized>(Arc<DynRawKey<S, T>>);
+
+impl<S: Schema, T: ?Sized + 'static> DynKey<S, T> {
+    /// Creates a key without a default value.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self
+    where
+        S: ValueBound<T, false>,
+    {
+        Self(Arc::new(DynRawKey::new(None)))
+    }
+
+    /// Creates a key with a default value created by `init` at the first access.
+    pub fn with_default(init: impl Fn(&CtxMap<S>) -> T + Send + Sync + 'static) -> Self
+    where
+        S: ValueBound<T, false> + InitBound<T>,
+        T: Sized,
+    {
+        Self(Arc::new(DynRawKey::new_with(init)))
+    }
+}
+
+impl<S: Schema, T: ?Sized> Clone for DynKey<S, T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// Information about a key captured by [`key`] macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInfo {
+    name: &'static str,
+    id: &'static str,
+    explicit_id: bool,
+    schema: &'static str,
+    type_name: &'static str,
+    file: &'static str,
+    line: u32,
+    is_mut: bool,
+    has_default: bool,
+    doc: &'static [&'static str],
+}
+
+impl KeyInfo {
+    /// The identifier of the key.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// The identifier of the key that is stable across builds.
+    ///
+    /// This is the module path and the name of the key joined with `::`, unless specified with `#[id(...)]` in [`key`] macro.
+    /// Ids specified with `#[id(...)]` must be unique within the schema.
+    /// Default ids are unique except for keys with the same name defined in function bodies of the same module.
+    pub fn id(&self) -> &'static str {
+        self.id
     }
-    fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T {
-        (self.to_mut)(<dyn Any>::downcast_mut::<V>(value).unwrap())
+    /// The schema of the key as written in [`key`] macro.
+    pub fn schema(&self) -> &'static str {
+        self.schema
     }
-    fn init(&self) -> Box<dyn Any> {
-        Box::new((self.init)())
+    /// The value type of the key as written in [`key`] macro.
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+    /// The file where the key is defined.
+    pub fn file(&self) -> &'static str {
+        self.file
+    }
+    /// The line where the key is defined.
+    ///
+    /// Keys defined in the same [`key`] macro invocation have the line of the invocation.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+    /// Returns `true` if the key is defined with `mut`.
+    pub fn is_mut(&self) -> bool {
+        self.is_mut
+    }
+    /// Returns `true` if the key has a default value.
+    pub fn has_default(&self) -> bool {
+        self.has_default
+    }
+    /// The documentation comment of the key.
+    pub fn doc(&self) -> String {
+        let lines: Vec<_> = self
+            .doc
+            .iter()
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}: {}` of schema `{}` defined at {}:{}",
+            self.name, self.type_name, self.schema, self.file, self.line
+        )
+    }
+}
+
+/// An error indicating that there is no value corresponding to a key.
+///
+/// Returned by [`CtxMap::require`].
+#[derive(Debug, Clone, Copy)]
+pub struct MissingKey(&'static KeyInfo);
+
+impl MissingKey {
+    /// Returns the information about the missing key.
+    pub fn key(&self) -> &'static KeyInfo {
+        self.0
+    }
+}
+
+impl fmt::Display for MissingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no value for key {}", self.0)
+    }
+}
+
+impl Error for MissingKey {}
+
+/// Key collection for [`CtxMap`].
+///
+/// Use [`schema`] macro to define a type that implement `Schema`.
+pub trait Schema: 'static + Sized {
+    #[doc(hidden)]
+    type Lock: MapLock;
+    #[doc(hidden)]
+    type Storage: Storage;
+    fn data() -> &'static SchemaData;
+    #[doc(hidden)]
+    fn current() -> &'static thread::LocalKey<Current<Self>>;
+
+    /// Returns the information about all keys of the schema, including keys that have not been used yet.
+    ///
+    /// The keys are sorted by the location where they are defined, and then by name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ctxmap::Schema;
+    ///
+    /// ctxmap::schema!(S);
+    /// ctxmap::key!(S {
+    ///     /// The first key.
+    ///     KEY_A: u16 = 10,
+    ///     mut KEY_B: str,
+    /// });
+    ///
+    /// let keys = S::keys();
+    /// assert_eq!(keys[0].name(), "KEY_A");
+    /// assert_eq!(keys[0].doc(), "The first key.");
+    /// assert!(keys[0].has_default());
+    /// assert_eq!(keys[1].name(), "KEY_B");
+    /// assert!(keys[1].is_mut());
+    /// ```
+    fn keys() -> Vec<&'static KeyInfo> {
+        helpers::keys::<Self>()
     }
 }
 
+/// [`Schema`] whose values are all thread-safe.
+///
+/// [`CtxMap`] implements [`Send`] and [`Sync`] if its schema implements `SyncSchema`.
+///
+/// Use [`schema`] macro with `sync` to define a type that implement `SyncSchema`.
+///
+/// # Safety
+///
+/// All keys of the schema must have a [`Sync`] value type (`Send + Sync` for mutable keys),
+/// and all default values must be `Send + Sync`.
+pub unsafe trait SyncSchema: Schema {}
+
 #[doc(hidden)]
 pub mod helpers {
-    use crate::{Key, KeyData, KeyDataValue, Schema};
+    use crate::{
+        fmt_value, init_default, Cloned, CtxMap, EntryState, FmtFn, InitError, Key, KeyInfo,
+        Schema, SnapshotFn, Value,
+    };
+    pub use inventory;
     use std::{
+        any::{type_name, Any, TypeId},
+        cell::{Cell, OnceCell, RefCell},
+        collections::BTreeMap,
+        error::Error,
+        fmt,
         marker::PhantomData,
+        mem::{self, MaybeUninit},
+        ptr::{self, NonNull},
         sync::{
-            atomic::{AtomicUsize, Ordering},
-            LazyLock,
+            atomic::{AtomicPtr, AtomicUsize, Ordering},
+            Mutex, MutexGuard, OnceLock, PoisonError, RwLock,
         },
     };
 
+    /// How the entries of [`CtxMap`] indexed by the index of the key are stored, chosen by the schema.
+    pub trait Storage: 'static {
+        type Table<T>: Table<T>;
+    }
+
+    /// The storage of schemas defined without `sparse`, a `Vec` resized to the largest index in use.
+    pub struct Dense;
+
+    /// The storage of schemas defined with `sparse`.
+    ///
+    /// Maps start with `Inline` and move to `Paged` when it is full,
+    /// so their memory usage depends on the number of keys in use rather than on the largest index.
+    pub struct Sparse;
+
+    impl Storage for Dense {
+        type Table<T> = DenseTable<T>;
+    }
+    impl Storage for Sparse {
+        type Table<T> = SparseTable<T>;
+    }
+
+    pub trait Table<T> {
+        fn new() -> Self;
+        /// Returns the entries if they are stored in a slice indexed by the index of the key.
+        fn as_dense(&self) -> Option<&[Option<T>]>;
+        fn get(&self, index: usize) -> Option<&T>;
+        /// Sets the entry of `index` and returns the previous entry.
+        fn replace(&mut self, index: usize, value: Option<T>) -> Option<T>;
+        fn iter(&self) -> Box<dyn Iterator<Item = (usize, &T)> + '_>;
+    }
+
+    pub struct DenseTable<T>(Vec<Option<T>>);
+
+    impl<T> Table<T> for DenseTable<T> {
+        fn new() -> Self {
+            Self(Vec::new())
+        }
+        #[inline]
+        fn as_dense(&self) -> Option<&[Option<T>]> {
+            Some(&self.0)
+        }
+        #[inline]
+        fn get(&self, index: usize) -> Option<&T> {
+            self.0.get(index)?.as_ref()
+        }
+        #[inline]
+        fn replace(&mut self, index: usize, value: Option<T>) -> Option<T> {
+            if let Some(e) = self.0.get_mut(index) {
+                return mem::replace(e, value);
+            }
+            self.replace_slow(index, value)
+        }
+        fn iter(&self) -> Box<dyn Iterator<Item = (usize, &T)> + '_> {
+            Box::new(
+                self.0
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, e)| Some((i, e.as_ref()?))),
+            )
+        }
+    }
+    impl<T> DenseTable<T> {
+        #[cold]
+        fn replace_slow(&mut self, index: usize, value: Option<T>) -> Option<T> {
+            value.as_ref()?;
+            self.0.resize_with(index + 1, || None);
+            self.0[index] = value;
+            None
+        }
+    }
+
+    pub enum SparseTable<T> {
+        /// Up to `INLINE_LEN` entries with their indices, searched linearly.
+        Inline([Option<(usize, T)>; INLINE_LEN]),
+        /// Pages of `PAGE_LEN` entries, allocated when an entry of the page is first set.
+        Paged(Vec<Option<Box<[Option<T>; PAGE_LEN]>>>),
+    }
+
+    const INLINE_LEN: usize = 4;
+    const PAGE_LEN: usize = 16;
+
+    impl<T> Table<T> for SparseTable<T> {
+        fn new() -> Self {
+            Self::Inline([const { None }; INLINE_LEN])
+        }
+        fn as_dense(&self) -> Option<&[Option<T>]> {
+            None
+        }
+        fn get(&self, index: usize) -> Option<&T> {
+            match self {
+                Self::Inline(entries) => entries
+                    .iter()
+                    .flatten()
+                    .find(|e| e.0 == index)
+                    .map(|e| &e.1),
+                Self::Paged(pages) => {
+                    pages.get(index / PAGE_LEN)?.as_ref()?[index % PAGE_LEN].as_ref()
+                }
+            }
+        }
+        fn replace(&mut self, index: usize, value: Option<T>) -> Option<T> {
+            match self {
+                Self::Inline(entries) => {
+                    if let Some(e) = entries
+                        .iter_mut()
+                        .find(|e| matches!(e, Some((i, _)) if *i == index))
+                    {
+                        return mem::replace(e, value.map(|value| (index, value))).map(|e| e.1);
+                    }
+                    let value = value?;
+                    if let Some(e) = entries.iter_mut().find(|e| e.is_none()) {
+                        *e = Some((index, value));
+                        return None;
+                    }
+                    let mut paged = Self::Paged(Vec::new());
+                    for (i, value) in mem::replace(entries, [const { None }; INLINE_LEN])
+                        .into_iter()
+                        .flatten()
+                    {
+                        paged.replace(i, Some(value));
+                    }
+                    paged.replace(index, Some(value));
+                    *self = paged;
+                    None
+                }
+                Self::Paged(pages) => {
+                    let page = index / PAGE_LEN;
+                    if pages.len() <= page {
+                        value.as_ref()?;
+                        pages.resize_with(page + 1, || None);
+                    }
+                    let page = match &mut pages[page] {
+                        Some(page) => page,
+                        None if value.is_none() => return None,
+                        page => page.insert(Box::new([const { None }; PAGE_LEN])),
+                    };
+                    mem::replace(&mut page[index % PAGE_LEN], value)
+                }
+            }
+        }
+        fn iter(&self) -> Box<dyn Iterator<Item = (usize, &T)> + '_> {
+            match self {
+                Self::Inline(entries) => Box::new(entries.iter().flatten().map(|(i, e)| (*i, e))),
+                Self::Paged(pages) => Box::new(
+                    pages
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(p, page)| Some((p, page.as_ref()?)))
+                        .flat_map(|(p, page)| {
+                            let entries = page.iter().enumerate();
+                            entries.filter_map(move |(i, e)| Some((p * PAGE_LEN + i, e.as_ref()?)))
+                        }),
+                ),
+            }
+        }
+    }
+
+    /// The lock of [`CtxMap`] chosen by the schema.
+    ///
+    /// Maps of schemas defined with `sync` use `Mutex<()>` because they can be shared between threads,
+    /// and other maps use `()`, which takes no space and does nothing.
+    pub trait MapLock: 'static {
+        /// `true` if the map can be shared between threads, so it must be locked even to read it.
+        const SYNC: bool;
+        type Guard<'a>;
+        fn new() -> Self;
+        fn lock(&self) -> Self::Guard<'_>;
+    }
+    impl MapLock for () {
+        const SYNC: bool = false;
+        type Guard<'a> = ();
+        fn new() -> Self {}
+        fn lock(&self) -> Self::Guard<'_> {}
+    }
+    impl MapLock for Mutex<()> {
+        const SYNC: bool = true;
+        type Guard<'a> = MutexGuard<'a, ()>;
+        fn new() -> Self {
+            Mutex::new(())
+        }
+        fn lock(&self) -> Self::Guard<'_> {
+            Mutex::lock(self).unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
     pub struct SchemaData {
         next: AtomicUsize,
+        free: Mutex<Vec<usize>>,
+        entries: OnceLock<Vec<&'static KeyEntry>>,
+        statics: OnceLock<StaticKeys>,
+        late_snapshots: RwLock<BTreeMap<usize, SnapshotFn>>,
+        types: RwLock<BTreeMap<(TypeId, bool), TypeKey>>,
+    }
+
+    /// The keys defined with [`key`](crate::key) macro, indexed by [`SchemaData::statics`].
+    struct StaticKeys {
+        /// The indices of the keys by the addresses of the keys.
+        indices: BTreeMap<usize, usize>,
+        snapshots: Vec<Option<SnapshotFn>>,
+        /// The keys by names, or `None` for names shared by several keys.
+        names: BTreeMap<&'static str, Option<&'static KeyEntry>>,
+    }
+
+    /// The id of a key with its location and address to order keys with the same default id.
+    ///
+    /// The address distinguishes keys defined at the same location by a macro.
+    type StaticKeyId = (&'static str, &'static str, u32, usize);
+
+    fn static_key_id(e: &KeyEntry) -> StaticKeyId {
+        (e.info.id, e.info.file, e.info.line, key_addr(e.key))
+    }
+    fn key_addr<K: ?Sized>(key: *const K) -> usize {
+        key as *const () as usize
     }
 
+    /// A leaked `Key<S, T>` whose `T` is identified by the `TypeId` in [`SchemaData::types`].
+    struct TypeKey(*const ());
+
+    // SAFETY: `Key<S, T>` is `Send + Sync` except for `PhantomData<S>`, and no value of `S` exists in it.
+    unsafe impl Send for TypeKey {}
+    unsafe impl Sync for TypeKey {}
+
     impl SchemaData {
         #[allow(clippy::new_without_default)]
         pub const fn new() -> Self {
             SchemaData {
                 next: AtomicUsize::new(0),
+                free: Mutex::new(Vec::new()),
+                entries: OnceLock::new(),
+                statics: OnceLock::new(),
+                late_snapshots: RwLock::new(BTreeMap::new()),
+                types: RwLock::new(BTreeMap::new()),
+            }
+        }
+        /// Returns the indices and names of the keys defined with [`key`](crate::key) macro.
+        ///
+        /// The keys are indexed in the order of their ids, so the indices do not depend on the order of the first use.
+        /// Keys with the same default id, which are defined in function bodies of the same module, are ordered by location,
+        /// and keys at the same location, which are expanded from the same macro call, by address.
+        /// Other keys are indexed after them.
+        fn statics<S: Schema>(&self) -> &StaticKeys {
+            self.statics.get_or_init(|| {
+                let mut names = BTreeMap::new();
+                for &e in key_entries::<S>() {
+                    if names.insert(e.info.name, Some(e)).is_some() {
+                        names.insert(e.info.name, None);
+                    }
+                }
+                let mut entries = key_entries::<S>().to_vec();
+                entries.sort_by_key(|e| static_key_id(e));
+                for w in entries.windows(2) {
+                    let (a, b) = (w[0].info, w[1].info);
+                    if a.id == b.id && (a.explicit_id || b.explicit_id) {
+                        panic!(
+                            "duplicate key id `{}` in schema `{}` ({}:{} and {}:{})",
+                            a.id, a.schema, a.file, a.line, b.file, b.line
+                        );
+                    }
+                }
+                self.next.store(entries.len(), Ordering::SeqCst);
+                let addrs = entries.iter().map(|e| key_addr(e.key));
+                StaticKeys {
+                    indices: addrs.enumerate().map(|(i, addr)| (addr, i)).collect(),
+                    snapshots: entries.iter().map(|e| (e.snapshot)(e.key)).collect(),
+                    names,
+                }
+            })
+        }
+        /// Returns the index of a key defined with [`key`](crate::key) macro that was registered after [`Self::statics`] was built,
+        /// for example by a library loaded later.
+        fn late_key_index<S: Schema, T: ?Sized, const MUT: bool>(
+            &self,
+            key: &'static Key<S, T, MUT>,
+        ) -> usize {
+            let index = self.push_key::<S>();
+            if let Some(f) = key.0.snapshot {
+                self.late_snapshots.write().unwrap().insert(index, f);
+            }
+            match key.0.index.compare_exchange(
+                usize::MAX,
+                index,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => index,
+                Err(current) => {
+                    self.late_snapshots.write().unwrap().remove(&index);
+                    self.release_key(index);
+                    current
+                }
+            }
+        }
+        fn push_key<S: Schema>(&self) -> usize {
+            self.statics::<S>();
+            self.next.fetch_add(1, Ordering::SeqCst)
+        }
+
+        /// Returns the index and generation of a new runtime key, reusing the index of a dropped one.
+        fn alloc_key<S: Schema>(&self) -> (usize, usize) {
+            static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+            let generation = NEXT_GENERATION.fetch_add(1, Ordering::SeqCst);
+            let index = self.free.lock().unwrap().pop();
+            (index.unwrap_or_else(|| self.push_key::<S>()), generation)
+        }
+        fn release_key(&self, index: usize) {
+            self.free.lock().unwrap().push(index);
+        }
+        pub(crate) fn snapshot_fn<S: Schema>(&self, index: usize) -> Option<SnapshotFn> {
+            match self.statics::<S>().snapshots.get(index) {
+                Some(f) => *f,
+                None => self.late_snapshots.read().unwrap().get(&index).copied(),
+            }
+        }
+        /// Returns the key for values keyed by `T`, creating it at the first call.
+        ///
+        /// The key is looked up in a cache of the thread first, so that only the first call on each thread takes the lock.
+        fn type_key<S: Schema, T: ?Sized + 'static>(
+            &self,
+            default: bool,
+            new: fn() -> RawKey<S, T>,
+        ) -> &'static Key<S, T> {
+            thread_local! {
+                static TYPE_KEYS: RefCell<BTreeMap<(TypeId, TypeId, bool), *const ()>> =
+                    const { RefCell::new(BTreeMap::new()) };
+            }
+            let cache_id = (TypeId::of::<S>(), TypeId::of::<T>(), default);
+            if let Some(p) = TYPE_KEYS.with_borrow(|c| c.get(&cache_id).copied()) {
+                return unsafe { &*(p as *const Key<S, T>) };
+            }
+            let key = self.type_key_slow(default, new);
+            TYPE_KEYS.with_borrow_mut(|c| c.insert(cache_id, key as *const Key<S, T> as *const ()));
+            key
+        }
+        #[cold]
+        fn type_key_slow<S: Schema, T: ?Sized + 'static>(
+            &self,
+            default: bool,
+            new: fn() -> RawKey<S, T>,
+        ) -> &'static Key<S, T> {
+            let id = (TypeId::of::<T>(), default);
+            let types = self.types.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(key) = types.get(&id) {
+                return unsafe { &*(key.0 as *const Key<S, T>) };
+            }
+            drop(types);
+
+            // The key is created without holding the lock, because `push_key` may panic while indexing the keys of the schema.
+            let info = key_info(
+                type_name::<T>(),
+                type_name::<T>(),
+                false,
+                type_name::<S>(),
+                type_name::<T>(),
+                "",
+                0,
+                false,
+                default,
+                &[],
+            );
+            let index = self.push_key::<S>();
+            let key = Box::new(Key(
+                RawKey {
+                    index: AtomicUsize::new(index),
+                    ..new()
+                },
+                info,
+            ));
+            let mut types = self.types.write().unwrap_or_else(PoisonError::into_inner);
+            if let Some(key) = types.get(&id) {
+                // Another thread created the key first.
+                let key = key.0;
+                drop(types);
+                self.release_key(index);
+                return unsafe { &*(key as *const Key<S, T>) };
+            }
+            let key: &'static Key<S, T> = Box::leak(key);
+            types.insert(id, TypeKey(key as *const Key<S, T> as *const ()));
+            key
+        }
+    }
+
+    pub(crate) fn type_key<S: Schema, T: ?Sized + 'static>() -> &'static Key<S, T> {
+        S::data().type_key(false, || RawKey::new(None))
+    }
+    pub(crate) fn type_default_key<S, T>() -> &'static Key<S, T>
+    where
+        S: Schema + ValueBound<T, false> + InitBound<T>,
+        T: Default + 'static,
+    {
+        S::data().type_key(true, || {
+            let data = RawKey::<S, T>::data_with(|_| T::default(), |x| x);
+            RawKey::<S, T>::new_with(Box::leak(Box::new(data)))
+        })
+    }
+
+    /// Looks up the index of a key defined with [`key`](crate::key) macro and caches it in the key.
+    #[cold]
+    pub(crate) fn static_key_index<S: Schema, T: ?Sized, const MUT: bool>(
+        key: &'static Key<S, T, MUT>,
+    ) -> usize {
+        let data = S::data();
+        let Some(&index) = data.statics::<S>().indices.get(&key_addr(key)) else {
+            return data.late_key_index(key);
+        };
+        key.0.index.store(index, Ordering::Relaxed);
+        index
+    }
+
+    /// A pointer to a value of any type, including unsized types.
+    ///
+    /// The type is not stored, so the pointer must be read with the type it was created with.
+    #[derive(Clone, Copy)]
+    pub struct RawPtr(MaybeUninit<[*const (); 2]>);
+
+    impl RawPtr {
+        pub(crate) fn new<T: ?Sized>(ptr: *const T) -> Self {
+            const { assert!(size_of::<*const T>() <= size_of::<[*const (); 2]>()) };
+            let mut raw = MaybeUninit::<[*const (); 2]>::uninit();
+            unsafe { raw.as_mut_ptr().cast::<*const T>().write(ptr) };
+            Self(raw)
+        }
+
+        /// # Safety
+        ///
+        /// `T` must be the type that the pointer was created with.
+        pub(crate) unsafe fn get<T: ?Sized>(self) -> *const T {
+            self.0.as_ptr().cast::<*const T>().read()
+        }
+    }
+
+    /// The current map of the thread, and the empty map used when there is no current map.
+    pub struct Current<S: Schema>(
+        pub(crate) Cell<Option<NonNull<CtxMap<S>>>>,
+        pub(crate) OnceCell<CtxMap<S>>,
+    );
+
+    impl<S: Schema> Current<S> {
+        #[allow(clippy::new_without_default)]
+        pub const fn new() -> Self {
+            Self(Cell::new(None), OnceCell::new())
+        }
+    }
+
+    /// Requirements for the value type of keys. `MUT` is the mutability of the key.
+    pub trait ValueBound<T: ?Sized, const MUT: bool> {}
+
+    /// Requirements for the type of default values.
+    pub trait InitBound<V> {}
+
+    pub trait KeyData<S: Schema, T: ?Sized>: Send + Sync {
+        fn get<'a>(&self, value: &'a dyn Any) -> &'a T;
+        fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T;
+        fn init(&self, map: &CtxMap<S>) -> Result<Box<dyn Any>, InitError>;
+    }
+
+    pub struct KeyDataValue<Init, ToRef, ToMut> {
+        init: Init,
+        to_ref: ToRef,
+        to_mut: ToMut,
+    }
+
+    impl<S, Init, ToRef, ToMut, V, T> KeyData<S, T> for KeyDataValue<Init, ToRef, ToMut>
+    where
+        S: Schema,
+        Init: InitFn<S, Value = V>,
+        ToRef: Send + Sync + Fn(&V) -> &T,
+        ToMut: Send + Sync + Fn(&mut V) -> &mut T,
+        V: 'static,
+        T: ?Sized,
+    {
+        fn get<'a>(&self, value: &'a dyn Any) -> &'a T {
+            (self.to_ref)(<dyn Any>::downcast_ref::<V>(value).unwrap())
+        }
+        fn get_mut<'a>(&self, value: &'a mut dyn Any) -> &'a mut T {
+            (self.to_mut)(<dyn Any>::downcast_mut::<V>(value).unwrap())
+        }
+        fn init(&self, map: &CtxMap<S>) -> Result<Box<dyn Any>, InitError> {
+            Ok(Box::new(self.init.init(map)?))
+        }
+    }
+
+    /// A function that creates a default value.
+    pub trait InitFn<S: Schema>: Send + Sync {
+        type Value;
+        fn init(&self, map: &CtxMap<S>) -> Result<Self::Value, InitError>;
+    }
+    impl<S: Schema, V, F> InitFn<S> for F
+    where
+        F: Send + Sync + Fn(&CtxMap<S>) -> V,
+    {
+        type Value = V;
+        fn init(&self, map: &CtxMap<S>) -> Result<V, InitError> {
+            Ok(self(map))
+        }
+    }
+
+    /// A fallible [`InitFn`].
+    pub struct Try<F>(F);
+
+    impl<S: Schema, V, E, F> InitFn<S> for Try<F>
+    where
+        F: Send + Sync + Fn(&CtxMap<S>) -> Result<V, E>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Value = V;
+        fn init(&self, map: &CtxMap<S>) -> Result<V, InitError> {
+            (self.0)(map).map_err(InitError::new)
+        }
+    }
+
+    type ToMutFn<V, T> = fn(&mut V) -> &mut T;
+
+    pub struct RawKey<S: Schema, T: ?Sized + 'static, const MUT: bool = false> {
+        pub(crate) schema: PhantomData<S>,
+        /// `usize::MAX` until the index is looked up by [`static_key_index`].
+        pub(crate) index: AtomicUsize,
+        pub(crate) data: Option<&'static dyn KeyData<S, T>>,
+        pub(crate) computed: bool,
+        pub(crate) shared: bool,
+        /// The default value of keys with `shared`, set at the first access.
+        pub(crate) shared_value: SharedDefault,
+        pub(crate) debug: fn() -> Option<DebugFn<T>>,
+        pub(crate) display: fn() -> Option<DisplayFn<T>>,
+        pub(crate) secret: bool,
+        pub(crate) snapshot: Option<SnapshotFn>,
+    }
+    pub type RawKeyMut<S, T> = RawKey<S, T, true>;
+
+    impl<S: Schema, T: ?Sized + 'static, const MUT: bool> RawKey<S, T, MUT> {
+        const fn new(data: Option<&'static dyn KeyData<S, T>>) -> Self {
+            const fn none<T: ?Sized>() -> Option<DebugFn<T>> {
+                None
+            }
+            Self {
+                schema: PhantomData,
+                index: AtomicUsize::new(usize::MAX),
+                data,
+                computed: false,
+                shared: false,
+                shared_value: SharedDefault(AtomicPtr::new(ptr::null_mut())),
+                debug: none,
+                display: none,
+                secret: false,
+                snapshot: None,
+            }
+        }
+        pub const fn new_without_default() -> Self
+        where
+            S: ValueBound<T, MUT>,
+        {
+            Self::new(None)
+        }
+        pub const fn debug(self, debug: fn() -> Option<DebugFn<T>>) -> Self {
+            Self { debug, ..self }
+        }
+        pub const fn display(self, display: fn() -> Option<DisplayFn<T>>) -> Self {
+            Self { display, ..self }
+        }
+        pub const fn secret(self) -> Self {
+            Self {
+                secret: true,
+                ..self
+            }
+        }
+    }
+    impl<S: Schema, T: Clone + 'static> RawKey<S, T, false> {
+        pub const fn cloneable(self) -> Self
+        where
+            S: InitBound<T>,
+        {
+            Self {
+                snapshot: Some(Cloned::<T>::snapshot),
+                ..self
+            }
+        }
+    }
+    impl<S: Schema, T: ?Sized + 'static> RawKey<S, T, false> {
+        pub const fn computed(self) -> Self {
+            assert!(!self.shared, "`computed` cannot be used with `shared`");
+            Self {
+                computed: true,
+                ..self
             }
         }
-        pub(crate) fn push_key(&self) -> usize {
-            self.next.fetch_add(1, Ordering::SeqCst)
+    }
+    impl<S: Schema, T: ?Sized + Sync + 'static> RawKey<S, T, false> {
+        pub const fn shared(self) -> Self {
+            assert!(!self.computed, "`shared` cannot be used with `computed`");
+            Self {
+                shared: true,
+                ..self
+            }
         }
     }
 
-    pub struct RawKey<S: Schema, T: ?Sized, const MUT: bool = false> {
-        pub(crate) schema: PhantomData<S>,
-        pub(crate) index: usize,
-        pub(crate) data: Option<Box<dyn KeyData<T>>>,
+    /// A default value shared by all maps. The value is leaked and never dropped.
+    pub struct SharedDefault(AtomicPtr<SharedValue>);
+
+    struct SharedValue {
+        _value: Box<dyn Any>,
+        ptr: RawPtr,
     }
-    pub type RawKeyMut<S, T> = RawKey<S, T, true>;
 
-    impl<S: Schema, T: ?Sized, const MUT: bool> RawKey<S, T, MUT> {
-        fn new(data: Option<Box<dyn KeyData<T>>>) -> Self {
+    // SAFETY: The value is only set for keys with `shared`, whose value type is `Sync`.
+    // The value is only accessed through `ptr` after it is set.
+    unsafe impl Send for SharedDefault {}
+    unsafe impl Sync for SharedDefault {}
+
+    impl SharedDefault {
+        pub(crate) fn get<S: Schema, T: ?Sized>(
+            &self,
+            index: usize,
+            key: &KeyInfo,
+            data: &dyn KeyData<S, T>,
+        ) -> Result<RawPtr, InitError> {
+            if let Some(p) = self.find() {
+                return Ok(p);
+            }
+            let value = init_default(ptr::null(), index, Some(key), || data.init(&CtxMap::new()))?;
+            let ptr = RawPtr::new::<T>(data.get(&*value));
+            let shared = Box::into_raw(Box::new(SharedValue { _value: value, ptr }));
+            match self.0.compare_exchange(
+                ptr::null_mut(),
+                shared,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => Ok(ptr),
+                Err(current) => {
+                    // Another thread initialized the value first.
+                    drop(unsafe { Box::from_raw(shared) });
+                    Ok(unsafe { (*current).ptr })
+                }
+            }
+        }
+        pub(crate) fn find(&self) -> Option<RawPtr> {
+            let p = self.0.load(Ordering::Acquire);
+            (!p.is_null()).then(|| unsafe { (*p).ptr })
+        }
+    }
+    impl<S: Schema, T: 'static> RawKey<S, [T], false> {
+        pub const fn accumulate(self) -> Self
+        where
+            S: InitBound<()>,
+        {
+            if self.data.is_some() {
+                return self;
+            }
             Self {
-                schema: PhantomData,
-                index: S::data().push_key(),
-                data,
+                data: Some(Empty::<S, T>::DATA),
+                ..self
             }
         }
     }
+
+    /// The default value of accumulating keys without a default value.
+    struct Empty<S, T>(PhantomData<(S, T)>);
+
+    impl<S: Schema, T: 'static> Empty<S, T> {
+        const DATA: &'static dyn KeyData<S, [T]> = &KeyDataValue {
+            init: Self::init as fn(&CtxMap<S>),
+            to_ref: Self::to_ref as fn(&()) -> &[T],
+            to_mut: to_mut_unreachable as ToMutFn<(), [T]>,
+        };
+
+        fn init(_: &CtxMap<S>) {}
+        fn to_ref(_: &()) -> &[T] {
+            &[]
+        }
+    }
+
+    fn to_mut_unreachable<V, T: ?Sized>(_: &mut V) -> &mut T {
+        unreachable!()
+    }
     impl<S: Schema, T: ?Sized + 'static> RawKey<S, T, false> {
-        pub fn new_with<Init, ToRef, V>(init: Init, to_ref: ToRef) -> Self
+        pub const fn new_with(data: &'static dyn KeyData<S, T>) -> Self
+        where
+            S: ValueBound<T, false>,
+        {
+            Self::new(Some(data))
+        }
+        pub const fn data_with<Init, ToRef, V>(
+            init: Init,
+            to_ref: ToRef,
+        ) -> KeyDataValue<Init, ToRef, ToMutFn<V, T>>
         where
-            Init: Send + Sync + Fn() -> V + 'static,
-            ToRef: Send + Sync + Fn(&V) -> &T + 'static,
+            S: InitBound<V>,
+            Init: Send + Sync + Fn(&CtxMap<S>) -> V,
+            ToRef: Send + Sync + Fn(&V) -> &T,
             V: 'static,
         {
-            fn to_mut_unreachable<V, T: ?Sized>(_: &mut V) -> &mut T {
-                unreachable!()
-            }
-            Self::new(Some(Box::new(KeyDataValue {
+            KeyDataValue {
                 init,
                 to_ref,
                 to_mut: to_mut_unreachable,
-            })))
+            }
+        }
+        pub const fn data_try_with<Init, ToRef, V, E>(
+            init: Init,
+            to_ref: ToRef,
+        ) -> KeyDataValue<Try<Init>, ToRef, ToMutFn<V, T>>
+        where
+            S: InitBound<V>,
+            Init: Send + Sync + Fn(&CtxMap<S>) -> Result<V, E>,
+            ToRef: Send + Sync + Fn(&V) -> &T,
+            V: 'static,
+            E: Into<Box<dyn Error + Send + Sync>>,
+        {
+            KeyDataValue {
+                init: Try(init),
+                to_ref,
+                to_mut: to_mut_unreachable,
+            }
         }
     }
     impl<S: Schema, T: ?Sized + 'static> RawKey<S, T, true> {
-        pub fn new_with_mut<Init, ToRef, ToMut, V>(init: Init, to_ref: ToRef, to_mut: ToMut) -> Self
+        pub const fn new_with(data: &'static dyn KeyData<S, T>) -> Self
+        where
+            S: ValueBound<T, true>,
+        {
+            Self::new(Some(data))
+        }
+        pub const fn data_with<Init, ToRef, ToMut, V>(
+            init: Init,
+            to_ref: ToRef,
+            to_mut: ToMut,
+        ) -> KeyDataValue<Init, ToRef, ToMut>
         where
-            Init: Send + Sync + Fn() -> V + 'static,
-            ToRef: Send + Sync + Fn(&V) -> &T + 'static,
-            ToMut: Send + Sync + Fn(&mut V) -> &mut T + 'static,
+            S: InitBound<V>,
+            Init: Send + Sync + Fn(&CtxMap<S>) -> V,
+            ToRef: Send + Sync + Fn(&V) -> &T,
+            ToMut: Send + Sync + Fn(&mut V) -> &mut T,
             V: 'static,
         {
-            Self::new(Some(Box::new(KeyDataValue {
+            KeyDataValue {
                 init,
                 to_ref,
                 to_mut,
-            })))
+            }
+        }
+        pub const fn data_try_with<Init, ToRef, ToMut, V, E>(
+            init: Init,
+            to_ref: ToRef,
+            to_mut: ToMut,
+        ) -> KeyDataValue<Try<Init>, ToRef, ToMut>
+        where
+            S: InitBound<V>,
+            Init: Send + Sync + Fn(&CtxMap<S>) -> Result<V, E>,
+            ToRef: Send + Sync + Fn(&V) -> &T,
+            ToMut: Send + Sync + Fn(&mut V) -> &mut T,
+            V: 'static,
+            E: Into<Box<dyn Error + Send + Sync>>,
+        {
+            KeyDataValue {
+                init: Try(init),
+                to_ref,
+                to_mut,
+            }
         }
     }
 
-    pub const fn new_key<S: Schema, T: ?Sized + 'static, const MUT: bool>() -> Key<S, T, MUT> {
-        Key(LazyLock::new(|| RawKey::new(None)))
+    /// The key of [`DynKey`](crate::DynKey). Releases its index when dropped.
+    pub struct DynRawKey<S: Schema, T: ?Sized> {
+        pub(crate) index: usize,
+        /// Unique among runtime keys because their index is reused. Static keys have generation zero.
+        pub(crate) generation: usize,
+        pub(crate) data: Option<Box<dyn KeyData<S, T>>>,
+    }
+
+    impl<S: Schema, T: ?Sized + 'static> DynRawKey<S, T> {
+        pub(crate) fn new(data: Option<Box<dyn KeyData<S, T>>>) -> Self {
+            let (index, generation) = S::data().alloc_key::<S>();
+            Self {
+                index,
+                generation,
+                data,
+            }
+        }
+        pub(crate) fn new_with<Init>(init: Init) -> Self
+        where
+            S: InitBound<T>,
+            Init: Send + Sync + Fn(&CtxMap<S>) -> T + 'static,
+            T: Sized,
+        {
+            Self::new(Some(Box::new(RawKey::<S, T>::data_with(init, |x| x))))
+        }
+    }
+    impl<S: Schema, T: ?Sized> Drop for DynRawKey<S, T> {
+        fn drop(&mut self) {
+            S::data().release_key(self.index);
+        }
     }
-    pub const fn new_key_with<S: Schema, T: ?Sized + 'static, const MUT: bool>(
-        f: fn() -> RawKey<S, T, MUT>,
+
+    pub const fn new_key<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: RawKey<S, T, MUT>,
+        info: KeyInfo,
     ) -> Key<S, T, MUT> {
-        Key(LazyLock::new(f))
+        Key(key, info)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub const fn key_info(
+        name: &'static str,
+        id: &'static str,
+        explicit_id: bool,
+        schema: &'static str,
+        type_name: &'static str,
+        file: &'static str,
+        line: u32,
+        is_mut: bool,
+        has_default: bool,
+        doc: &'static [&'static str],
+    ) -> KeyInfo {
+        KeyInfo {
+            name,
+            id,
+            explicit_id,
+            schema,
+            type_name,
+            file,
+            line,
+            is_mut,
+            has_default,
+            doc,
+        }
+    }
+
+    pub struct KeyEntry {
+        schema: fn() -> TypeId,
+        info: &'static KeyInfo,
+        key: ErasedKey,
+        fmt: fn(ErasedKey, &dyn Any, &mut fmt::DebugStruct),
+        value: for<'a> fn(ErasedKey, &'a dyn Any) -> Option<(EntryState, Value<'a>)>,
+        get: for<'a> fn(ErasedKey, &'a dyn Any) -> Option<Value<'a>>,
+        snapshot: fn(ErasedKey) -> Option<SnapshotFn>,
+    }
+    pub(crate) type ErasedKey = &'static (dyn Any + Sync);
+    inventory::collect!(KeyEntry);
+
+    impl KeyEntry {
+        pub(crate) fn info(&self) -> &'static KeyInfo {
+            self.info
+        }
+        pub(crate) fn value<'a, S: Schema>(
+            &self,
+            map: &'a CtxMap<S>,
+        ) -> Option<(EntryState, Value<'a>)> {
+            (self.value)(self.key, map)
+        }
+        pub(crate) fn get<'a, S: Schema>(&self, map: &'a CtxMap<S>) -> Option<Value<'a>> {
+            (self.get)(self.key, map)
+        }
+    }
+
+    pub const fn key_entry<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: &'static Key<S, T, MUT>,
+    ) -> KeyEntry
+    where
+        Key<S, T, MUT>: Sync,
+    {
+        KeyEntry {
+            schema: TypeId::of::<S>,
+            info: &key.1,
+            key,
+            fmt: fmt_key::<S, T, MUT>,
+            value: key_value::<S, T, MUT>,
+            get: key_get::<S, T, MUT>,
+            snapshot: key_snapshot::<S, T, MUT>,
+        }
+    }
+    fn key_snapshot<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: ErasedKey,
+    ) -> Option<SnapshotFn> {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key).unwrap();
+        key.0.snapshot
+    }
+    fn key_value<'a, S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key_any: ErasedKey,
+        map: &'a dyn Any,
+    ) -> Option<(EntryState, Value<'a>)> {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key_any).unwrap();
+        let map = map.downcast_ref::<CtxMap<S>>().unwrap();
+        let (state, value) = map.visible_entry(key)?;
+        Some((state, new_value::<S, T, MUT>(key_any, value)))
+    }
+    fn key_get<'a, S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key_any: ErasedKey,
+        map: &'a dyn Any,
+    ) -> Option<Value<'a>> {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key_any).unwrap();
+        let map = map.downcast_ref::<CtxMap<S>>().unwrap();
+        Some(new_value::<S, T, MUT>(key_any, map.get(key)?))
+    }
+    fn new_value<'a, S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key_any: ErasedKey,
+        value: &'a T,
+    ) -> Value<'a> {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key_any).unwrap();
+        let display: Option<FmtFn> = (key.0.display)().map(|_| display_key_value::<S, T, MUT> as _);
+        Value {
+            ptr: RawPtr::new::<T>(value),
+            type_id: TypeId::of::<T>(),
+            key: key_any,
+            fmt: fmt_key_value::<S, T, MUT>,
+            display,
+            _marker: PhantomData,
+        }
+    }
+    fn fmt_key_value<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: ErasedKey,
+        ptr: RawPtr,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key).unwrap();
+        let value = unsafe { &*ptr.get::<T>() };
+        fmt_value(value, (key.0.debug)(), key.0.secret, f)
+    }
+    fn display_key_value<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: ErasedKey,
+        ptr: RawPtr,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key).unwrap();
+        let value = unsafe { &*ptr.get::<T>() };
+        fmt_value(value, (key.0.display)(), key.0.secret, f)
+    }
+    fn fmt_key<S: Schema, T: ?Sized + 'static, const MUT: bool>(
+        key: ErasedKey,
+        map: &dyn Any,
+        f: &mut fmt::DebugStruct,
+    ) {
+        let key = <dyn Any>::downcast_ref::<Key<S, T, MUT>>(key).unwrap();
+        let map = map.downcast_ref::<CtxMap<S>>().unwrap();
+        f.field(key.1.name, &map.debug_entry(key));
+    }
+
+    /// Returns the keys of the schema sorted by location, collected at the first call.
+    pub(crate) fn key_entries<S: Schema>() -> &'static [&'static KeyEntry] {
+        S::data().entries.get_or_init(|| {
+            let mut entries: Vec<_> = inventory::iter::<KeyEntry>
+                .into_iter()
+                .filter(|e| (e.schema)() == TypeId::of::<S>())
+                .collect();
+            entries.sort_by_key(|e| (e.info.file, e.info.line, e.info.name));
+            entries
+        })
+    }
+    pub(crate) fn key_entry_by_name<S: Schema>(name: &str) -> Option<&'static KeyEntry> {
+        *S::data().statics::<S>().names.get(name)?
+    }
+    pub(crate) fn keys<S: Schema>() -> Vec<&'static KeyInfo> {
+        key_entries::<S>().iter().map(|e| e.info).collect()
+    }
+    pub(crate) fn fmt_map<S: Schema>(map: &CtxMap<S>, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("CtxMap");
+        for e in key_entries::<S>() {
+            (e.fmt)(e.key, map, &mut d);
+        }
+        d.finish()
+    }
+
+    pub type DebugFn<T> = fn(&T, &mut fmt::Formatter) -> fmt::Result;
+
+    pub struct FmtProbe<T: ?Sized>(PhantomData<T>);
+
+    impl<T: ?Sized> FmtProbe<T> {
+        #[allow(clippy::new_without_default)]
+        pub fn new() -> Self {
+            Self(PhantomData)
+        }
+    }
+
+    pub trait DebugFnSome<T: ?Sized> {
+        fn debug_fn(&self) -> Option<DebugFn<T>>;
+    }
+    impl<T: ?Sized + fmt::Debug> DebugFnSome<T> for FmtProbe<T> {
+        fn debug_fn(&self) -> Option<DebugFn<T>> {
+            Some(<T as fmt::Debug>::fmt)
+        }
+    }
+
+    pub trait DebugFnNone<T: ?Sized> {
+        fn debug_fn(&self) -> Option<DebugFn<T>>;
+    }
+    impl<T: ?Sized> DebugFnNone<T> for &FmtProbe<T> {
+        fn debug_fn(&self) -> Option<DebugFn<T>> {
+            None
+        }
+    }
+
+    pub type DisplayFn<T> = fn(&T, &mut fmt::Formatter) -> fmt::Result;
+
+    pub trait DisplayFnSome<T: ?Sized> {
+        fn display_fn(&self) -> Option<DisplayFn<T>>;
+    }
+    impl<T: ?Sized + fmt::Display> DisplayFnSome<T> for FmtProbe<T> {
+        fn display_fn(&self) -> Option<DisplayFn<T>> {
+            Some(<T as fmt::Display>::fmt)
+        }
+    }
+
+    pub trait DisplayFnNone<T: ?Sized> {
+        fn display_fn(&self) -> Option<DisplayFn<T>>;
+    }
+    impl<T: ?Sized> DisplayFnNone<T> for &FmtProbe<T> {
+        fn display_fn(&self) -> Option<DisplayFn<T>> {
+            None
+        }
     }
 }
 
@@ -481,17 +3423,88 @@ pub mod helpers {
 /// ctxmap::schema!(S1);
 /// ctxmap::schema!(pub S2);
 /// ```
+///
+/// With `sync`, the type also implements [`SyncSchema`].
+/// Keys of the schema must have a [`Sync`] value type (`Send + Sync` for mutable keys),
+/// and default values must be `Send + Sync`.
+///
+/// ```
+/// ctxmap::schema!(sync S);
+/// ctxmap::key!(S {
+///     KEY_1: u8 = 10,
+///     KEY_2: dyn std::fmt::Display + Sync = 20,
+///     mut KEY_3: String = String::new(),
+/// });
+///
+/// let m = ctxmap::CtxMap::<S>::new();
+/// std::thread::spawn(move || assert_eq!(m[&KEY_1], 10)).join().unwrap();
+/// ```
+///
+/// With `sparse`, maps of the schema store values in a sparse table instead of a `Vec` indexed by keys.
+/// A few values are stored inline, and more values are stored in pages allocated on demand,
+/// so maps only allocate memory for the keys in use even if the schema has hundreds of keys.
+/// Reading values is slightly slower than with the default storage, and the inline values make `CtxMap` itself larger.
+///
+/// ```
+/// ctxmap::schema!(sparse S1);
+/// ctxmap::schema!(pub sync sparse S2);
+/// ```
 #[macro_export]
 macro_rules! schema {
-    ($vis:vis $id:ident) => {
+    (@impl $vis:vis $id:ident, $lock:ty, $storage:ident) => {
         $vis struct $id;
         impl $crate::Schema for $id {
+            type Lock = $lock;
+            type Storage = $crate::helpers::$storage;
             fn data() -> &'static $crate::helpers::SchemaData {
                 static DATA: $crate::helpers::SchemaData = $crate::helpers::SchemaData::new();
                 &DATA
             }
+            fn current() -> &'static ::std::thread::LocalKey<$crate::helpers::Current<Self>> {
+                ::std::thread_local! {
+                    static CURRENT: $crate::helpers::Current<$id> = const { $crate::helpers::Current::new() };
+                }
+                &CURRENT
+            }
         }
-
+    };
+    (@sync $id:ident) => {
+        unsafe impl $crate::SyncSchema for $id {}
+        impl<T> $crate::helpers::ValueBound<T, false> for $id
+        where
+            T: ?::std::marker::Sized + ::std::marker::Sync,
+        {
+        }
+        impl<T> $crate::helpers::ValueBound<T, true> for $id
+        where
+            T: ?::std::marker::Sized + ::std::marker::Send + ::std::marker::Sync,
+        {
+        }
+        impl<V> $crate::helpers::InitBound<V> for $id
+        where
+            V: ::std::marker::Send + ::std::marker::Sync,
+        {
+        }
+    };
+    (@unsync $id:ident) => {
+        impl<T: ?::std::marker::Sized, const MUT: bool> $crate::helpers::ValueBound<T, MUT> for $id {}
+        impl<V> $crate::helpers::InitBound<V> for $id {}
+    };
+    ($vis:vis sync sparse $id:ident) => {
+        $crate::schema!(@impl $vis $id, ::std::sync::Mutex<()>, Sparse);
+        $crate::schema!(@sync $id);
+    };
+    ($vis:vis sync $id:ident) => {
+        $crate::schema!(@impl $vis $id, ::std::sync::Mutex<()>, Dense);
+        $crate::schema!(@sync $id);
+    };
+    ($vis:vis sparse $id:ident) => {
+        $crate::schema!(@impl $vis $id, (), Sparse);
+        $crate::schema!(@unsync $id);
+    };
+    ($vis:vis $id:ident) => {
+        $crate::schema!(@impl $vis $id, (), Dense);
+        $crate::schema!(@unsync $id);
     };
 }
 
@@ -535,6 +3548,143 @@ macro_rules! schema {
 /// });
 /// ```
 ///
+/// The default value can read other keys by writing it as a closure that takes the map.
+///
+/// The default value is initialized at the first access, using the values of the map at that time.
+/// If it reads values set by [`with`](CtxMap::with), it is initialized again after the scope of `with` ends.
+/// If the default values of keys depend on each other cyclically, reading them panics.
+/// To use a closure itself as the default value, enclose it in parentheses.
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     KEY_BASE: u32 = 10,
+///     KEY_TIMEOUT: u32 = |m| m[&KEY_BASE] * 3,
+///     KEY_F: dyn Fn(u32) -> u32 + Send + Sync = (|x| x + 1),
+/// });
+///
+/// let m = ctxmap::CtxMap::new();
+/// assert_eq!(m[&KEY_TIMEOUT], 30);
+/// assert_eq!(m[&KEY_F](1), 2);
+/// ```
+///
+/// The default value can be fallible by prefixing it with `try`.
+///
+/// The expression must return `Result`, and the error can be handled with [`try_get`](CtxMap::try_get).
+/// To return errors of the default values of other keys, including cycles, read them with `try_get` and `?`,
+/// because reading them with other methods panics.
+///
+/// ```
+/// use ctxmap::InitError;
+///
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     KEY_1: u32 = try "10".parse::<u32>(),
+///     KEY_2: u32 = try |m| m[&KEY_1].checked_mul(3).ok_or("overflow"),
+///     KEY_3: u32 = try "x".parse::<u32>(),
+///     KEY_4: u32 = try |m| Ok::<_, InitError>(m.try_get(&KEY_3)?.unwrap() + 1),
+/// });
+///
+/// let m = ctxmap::CtxMap::new();
+/// assert_eq!(m.try_get(&KEY_2).unwrap(), Some(&30));
+/// assert!(m.try_get(&KEY_4).is_err());
+/// ```
+///
+/// You can make a key computed with `computed`.
+///
+/// The default value of computed keys is recomputed when the keys it reads are changed by [`with`](CtxMap::with),
+/// and the computed value is discarded when the scope that changed them ends.
+/// Changes made through [`get_mut`](CtxMap::get_mut) are not tracked.
+/// `computed` can only be used with keys without `mut`.
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     KEY_USER_LOCALE: str,
+///     KEY_DEFAULT_LOCALE: str = "en",
+///     computed KEY_LOCALE: String = |m| {
+///         m.get(&KEY_USER_LOCALE).unwrap_or(&m[&KEY_DEFAULT_LOCALE]).to_string()
+///     },
+/// });
+///
+/// let mut m = ctxmap::CtxMap::new();
+/// assert_eq!(m[&KEY_LOCALE], "en");
+/// m.with(&KEY_USER_LOCALE, "ja", |m| {
+///     assert_eq!(m[&KEY_LOCALE], "ja");
+/// });
+/// assert_eq!(m[&KEY_LOCALE], "en");
+/// ```
+///
+/// You can make the default value of a key shared by all maps with `shared`.
+///
+/// The default value of shared keys is created once for the process, in a map without bindings,
+/// and stored in the key instead of each map, so reading it from a new map does not allocate.
+/// `shared` can only be used with keys without `mut` and `computed`, and the value type must implement [`Sync`].
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S { shared KEY_NAME: str = "abc" });
+///
+/// let m1 = ctxmap::CtxMap::new();
+/// let m2 = ctxmap::CtxMap::new();
+/// assert!(std::ptr::eq(&m1[&KEY_NAME], &m2[&KEY_NAME]));
+/// ```
+///
+/// Values of keys whose value type implements [`Debug`](std::fmt::Debug) are printed by the `Debug` implementation of [`CtxMap`].
+/// For other types, a function to format the value can be specified with `#[debug(...)]`.
+/// Values of keys with `secret` are printed as `<redacted>`.
+/// Default values that have not been initialized yet are printed as `Uninitialized`, and are not initialized by formatting.
+/// Like [`CtxMap::iter`], values set by [`CtxMap::with_type`] or [`CtxMap::with_dyn`] are not printed.
+///
+/// ```
+/// use std::fmt::{Display, Formatter, Result};
+///
+/// fn fmt_display(value: &(dyn Display + 'static), f: &mut Formatter) -> Result {
+///     write!(f, "{value}")
+/// }
+///
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     KEY_1: u8 = 10,
+///     #[debug(fmt_display)]
+///     KEY_2: dyn Display = 20,
+///     secret KEY_3: str = "password",
+///     KEY_4: u8,
+/// });
+///
+/// let m = ctxmap::CtxMap::<S>::new();
+/// assert_eq!(
+///     format!("{m:?}"),
+///     "CtxMap { KEY_1: Uninitialized, KEY_2: Uninitialized, KEY_3: Uninitialized, KEY_4: Missing }"
+/// );
+/// assert_eq!(m[&KEY_1], 10);
+/// assert_eq!(&m[&KEY_3], "password");
+/// assert_eq!(
+///     format!("{m:?}"),
+///     "CtxMap { KEY_1: Default(10), KEY_2: Uninitialized, KEY_3: Default(<redacted>), KEY_4: Missing }"
+/// );
+/// ```
+///
+/// Keys with the same name can be defined in different modules of the same schema,
+/// but such keys cannot be looked up by [`CtxMap::get_by_name`].
+///
+/// Each key has an id, which is the module path and the name of the key by default.
+/// The id can be specified with `#[id(...)]` to keep it stable when the key is moved to another module.
+/// Ids specified with `#[id(...)]` must be unique within the schema, and duplicates cause a panic when a key of the schema is first used.
+/// Keys are indexed in the order of their ids, so the indices do not depend on which key is used first.
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     KEY_1: u8,
+///     #[id("tenant")]
+///     KEY_2: u8,
+/// });
+///
+/// assert_eq!(KEY_1.info().id(), concat!(module_path!(), "::KEY_1"));
+/// assert_eq!(KEY_2.info().id(), "tenant");
+/// ```
+///
 /// You can specify mutability.
 ///
 /// Keys with `mut` can be used in [`with_mut`](CtxMap::with_mut), [`get_mut`](CtxMap::get_mut) and [`index_mut`](CtxMap::index_mut).
@@ -547,6 +3697,32 @@ macro_rules! schema {
 /// });
 /// ```
 ///
+/// You can make a key cloneable with `clone`.
+///
+/// Values of cloneable keys are captured by [`snapshot`](CtxMap::snapshot).
+/// `clone` can only be used with keys without `mut`, and the value type must implement [`Clone`].
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     clone KEY_1: u8,
+///     clone KEY_2: String = String::new(),
+/// });
+/// ```
+///
+/// You can make a key accumulating with `acc`.
+///
+/// The value type of accumulating keys must be a slice, and the default value is an empty slice.
+/// Use [`with_append`](CtxMap::with_append) to extend the value of enclosing scopes.
+///
+/// ```
+/// ctxmap::schema!(S);
+/// ctxmap::key!(S {
+///     acc KEY_1: [u8],
+///     acc KEY_2: [String],
+/// });
+/// ```
+///
 /// You can specify visibility.
 ///
 /// ```
@@ -557,28 +3733,161 @@ macro_rules! schema {
 /// ```
 #[macro_export]
 macro_rules! key {
-    ($schema:ty { }) => { };
-    ($schema:ty { $vis:vis $id:ident: $type:ty }) => {
-        $vis static $id: $crate::Key<$schema, $type> = $crate::helpers::new_key();
+    (@key $schema:ty;) => {};
+    (@key $schema:ty; $($tt:tt)*) => {
+        $crate::key!(@attrs $schema; [] [] []; $($tt)*);
     };
-    ($schema:ty { $vis:vis mut $id:ident: $type:ty }) => {
-        $vis static $id: $crate::KeyMut<$schema, $type> = $crate::helpers::new_key();
+    (@attrs $schema:ty; $attrs:tt $debug:tt $key_id:tt; #[debug($f:expr)] $($tt:tt)*) => {
+        $crate::key!(@attrs $schema; $attrs [$f] $key_id; $($tt)*);
     };
-    ($schema:ty { $vis:vis $id:ident: $type:ty = $init:expr }) => {
-        $vis static $id: $crate::Key<$schema, $type> =
-            $crate::helpers::new_key_with(|| $crate::helpers::RawKey::<_, $type>::new_with(|| $init, |x| x));
+    (@attrs $schema:ty; $attrs:tt $debug:tt $key_id:tt; #[id($i:literal)] $($tt:tt)*) => {
+        $crate::key!(@attrs $schema; $attrs $debug [$i]; $($tt)*);
     };
-    ($schema:ty { $vis:vis mut $id:ident: $type:ty = $init:expr }) => {
-        $vis static $id: $crate::KeyMut<$schema, $type> =
-            $crate::helpers::new_key_with(|| $crate::helpers::RawKeyMut::<_, $type>::new_with_mut(|| $init, |x| x, |x| x));
+    (@attrs $schema:ty; [$($attr:tt)*] $debug:tt $key_id:tt; #[$($a:tt)*] $($tt:tt)*) => {
+        $crate::key!(@attrs $schema; [$($attr)* #[$($a)*]] $debug $key_id; $($tt)*);
     };
-    ($schema:ty { $vis:vis $id:ident: $type:ty $(= $init:expr)?, $($tt:tt)* }) => {
-        $crate::key!($schema { $vis $id: $type $(= $init)? });
-        $crate::key!($schema { $($tt)* });
+    (@attrs $schema:ty; $attrs:tt $debug:tt $key_id:tt; $vis:vis mut $($tt:tt)*) => {
+        $crate::key!(@mods $schema; [$attrs $debug $key_id] [$vis] true []; $($tt)*);
     };
-    ($schema:ty { $vis:vis mut $id:ident: $type:ty $(= $init:expr)?, $($tt:tt)* }) => {
-        $crate::key!($schema { $vis mut $id: $type $(= $init)? });
-        $crate::key!($schema { $($tt)* });
+    (@attrs $schema:ty; $attrs:tt $debug:tt $key_id:tt; $vis:vis $m:ident $($tt:tt)*) => {
+        $crate::key!(@mods $schema; [$attrs $debug $key_id] [$vis] false []; $m $($tt)*);
+    };
+    (@mods $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt; $id:ident: $($tt:tt)*) => {
+        $crate::key!(@type $schema; $attrs $vis $mut $mods $id; $($tt)*);
+    };
+    (@mods $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt; mut $($tt:tt)*) => {
+        $crate::key!(@mods $schema; $attrs $vis true $mods; $($tt)*);
+    };
+    (@mods $schema:ty; $attrs:tt $vis:tt $mut:tt [$($mods:ident)*]; $m:ident $($tt:tt)*) => {
+        $crate::key!(@mods $schema; $attrs $vis $mut [$($mods)* $m]; $($tt)*);
+    };
+    (@type $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt $id:ident; $type:ty = try |$m:ident| $init:expr $(, $($tt:tt)*)?) => {
+        $crate::key!(@emit $schema; $attrs $vis $mut $mods $id; $type; (data_try_with |$m| $init));
+        $crate::key!(@key $schema; $($($tt)*)?);
+    };
+    (@type $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt $id:ident; $type:ty = try $init:expr $(, $($tt:tt)*)?) => {
+        $crate::key!(@emit $schema; $attrs $vis $mut $mods $id; $type; (data_try_with |_| $init));
+        $crate::key!(@key $schema; $($($tt)*)?);
+    };
+    (@type $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt $id:ident; $type:ty = |$m:ident| $init:expr $(, $($tt:tt)*)?) => {
+        $crate::key!(@emit $schema; $attrs $vis $mut $mods $id; $type; (data_with |$m| $init));
+        $crate::key!(@key $schema; $($($tt)*)?);
+    };
+    (@type $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt $id:ident; $type:ty = $init:expr $(, $($tt:tt)*)?) => {
+        $crate::key!(@emit $schema; $attrs $vis $mut $mods $id; $type; (data_with |_| $init));
+        $crate::key!(@key $schema; $($($tt)*)?);
+    };
+    (@type $schema:ty; $attrs:tt $vis:tt $mut:tt $mods:tt $id:ident; $type:ty $(, $($tt:tt)*)?) => {
+        $crate::key!(@emit $schema; $attrs $vis $mut $mods $id; $type;);
+        $crate::key!(@key $schema; $($($tt)*)?);
+    };
+    (@emit $schema:ty; [[$($attr:tt)*] [$($debug:expr)?] [$($key_id:literal)?]] [$vis:vis] $mut:tt [$($mods:ident)*] $id:ident; $type:ty; $(($new:ident $init:expr))?) => {
+        $($attr)*
+        $vis static $id: $crate::Key<$schema, $type, $mut> = $crate::helpers::new_key({
+            let key = $crate::key!(@new $mut $type $(, $new $init)?);
+            let key = key.debug(|| $crate::key!(@debug $type $(, $debug)?));
+            let key = key.display(|| $crate::key!(@display $type));
+            $(let key = $crate::key!(@mod $mods key);)*
+            key
+        }, $crate::helpers::key_info(
+            ::std::stringify!($id),
+            $crate::key!(@id $id $($key_id)?),
+            $crate::key!(@explicit_id $($key_id)?),
+            ::std::stringify!($schema),
+            ::std::stringify!($type),
+            ::std::file!(),
+            ::std::line!(),
+            $mut,
+            $crate::key!(@has_default [$($mods)*] $($new)?),
+            $crate::key!(@doc []; $($attr)*),
+        ));
+        $crate::key!(@submit $id []; $($attr)*);
+    };
+    (@submit $id:ident [$($cfg:tt)*];) => {
+        $($cfg)*
+        $crate::helpers::inventory::submit! {
+            $crate::helpers::key_entry(&$id)
+        }
+    };
+    (@submit $id:ident [$($cfg:tt)*]; #[cfg $($c:tt)*] $($attr:tt)*) => {
+        $crate::key!(@submit $id [$($cfg)* #[cfg $($c)*]]; $($attr)*);
+    };
+    (@submit $id:ident $cfg:tt; #[$($a:tt)*] $($attr:tt)*) => {
+        $crate::key!(@submit $id $cfg; $($attr)*);
+    };
+    (@has_default []) => {
+        false
+    };
+    (@has_default [] $new:ident) => {
+        true
+    };
+    (@has_default [acc $($mods:ident)*] $($new:ident)?) => {
+        true
+    };
+    (@has_default [$m:ident $($mods:ident)*] $($new:ident)?) => {
+        $crate::key!(@has_default [$($mods)*] $($new)?)
+    };
+    (@doc [$($doc:literal)*];) => {
+        &[$($doc),*]
+    };
+    (@doc [$($doc:literal)*]; #[doc = $d:literal] $($attr:tt)*) => {
+        $crate::key!(@doc [$($doc)* $d]; $($attr)*)
+    };
+    (@doc $doc:tt; #[$($a:tt)*] $($attr:tt)*) => {
+        $crate::key!(@doc $doc; $($attr)*)
+    };
+    (@debug $type:ty) => {{
+        use $crate::helpers::{DebugFnNone as _, DebugFnSome as _};
+        (&$crate::helpers::FmtProbe::<$type>::new()).debug_fn()
+    }};
+    (@debug $type:ty, $debug:expr) => {
+        ::std::option::Option::Some($debug as $crate::helpers::DebugFn<$type>)
+    };
+    (@display $type:ty) => {{
+        use $crate::helpers::{DisplayFnNone as _, DisplayFnSome as _};
+        (&$crate::helpers::FmtProbe::<$type>::new()).display_fn()
+    }};
+    (@id $id:ident) => {
+        ::std::concat!(::std::module_path!(), "::", ::std::stringify!($id))
+    };
+    (@id $id:ident $key_id:literal) => {
+        $key_id
+    };
+    (@explicit_id) => {
+        false
+    };
+    (@explicit_id $key_id:literal) => {
+        true
+    };
+    (@new $mut:tt $type:ty) => {
+        $crate::helpers::RawKey::<_, $type, $mut>::new_without_default()
+    };
+    (@new false $type:ty, $data:ident $init:expr) => {
+        $crate::helpers::RawKey::<_, $type>::new_with(
+            &$crate::helpers::RawKey::<_, $type>::$data($init, |x| x),
+        )
+    };
+    (@new true $type:ty, $data:ident $init:expr) => {
+        $crate::helpers::RawKeyMut::<_, $type>::new_with(
+            &$crate::helpers::RawKeyMut::<_, $type>::$data($init, |x| x, |x| x),
+        )
+    };
+    (@mod clone $key:ident) => {
+        $key.cloneable()
+    };
+    (@mod acc $key:ident) => {
+        $key.accumulate()
+    };
+    (@mod computed $key:ident) => {
+        $key.computed()
+    };
+    (@mod shared $key:ident) => {
+        $key.shared()
+    };
+    (@mod secret $key:ident) => {
+        $key.secret()
+    };
+    ($schema:ty { $($tt:tt)* }) => {
+        $crate::key!(@key $schema; $($tt)*);
     };
-
 }
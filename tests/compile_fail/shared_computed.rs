@@ -0,0 +1,5 @@
+fn main() {
+    ctxmap::schema!(Schema);
+    ctxmap::key!(Schema { shared computed KEY_A: u8 = 1 });
+    let _ = &KEY_A;
+}
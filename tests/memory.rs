@@ -0,0 +1,119 @@
+use ctxmap::{CtxMap, DynKey, Schema};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + layout.size()));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Returns the number of bytes allocated by `f` on the current thread.
+fn allocated(f: impl FnOnce()) -> usize {
+    let start = ALLOCATED.with(|a| a.get());
+    f();
+    ALLOCATED.with(|a| a.get()) - start
+}
+
+/// Returns the number of bytes allocated by a new map that uses only the last key.
+fn allocated_by_last_key<S: Schema>(keys: &[DynKey<S, u32>]) -> usize {
+    let key = keys.last().unwrap();
+    allocated(|| {
+        let mut m = CtxMap::<S>::new();
+        assert_eq!(m.get_dyn(key), Some(&1));
+        m.with_dyn(key, &2, |m| assert_eq!(m.get_dyn(key), Some(&2)));
+    })
+}
+
+ctxmap::schema!(DenseSchema);
+ctxmap::schema!(sparse SparseSchema);
+
+#[test]
+fn sparse_late_key() {
+    let dense_keys: Vec<_> = (0..500).map(|_| DynKey::with_default(|_| 1)).collect();
+    let sparse_keys: Vec<_> = (0..500).map(|_| DynKey::with_default(|_| 1)).collect();
+    let dense = allocated_by_last_key::<DenseSchema>(&dense_keys);
+    let sparse = allocated_by_last_key::<SparseSchema>(&sparse_keys);
+    assert!(dense >= 500 * size_of::<usize>(), "dense: {dense}");
+    assert!(sparse <= 64, "sparse: {sparse}");
+}
+
+ctxmap::schema!(sparse PagedSchema);
+
+#[test]
+fn sparse_many_keys() {
+    let keys: Vec<_> = (0..500)
+        .map(|_| DynKey::<PagedSchema, u32>::new())
+        .collect();
+    let used = [&keys[10], &keys[11], &keys[250], &keys[480], &keys[499]];
+    let bytes = allocated(|| {
+        let mut m = CtxMap::<PagedSchema>::new();
+        m.with_dyn(used[0], &0, |m| {
+            m.with_dyn(used[1], &1, |m| {
+                m.with_dyn(used[2], &2, |m| {
+                    m.with_dyn(used[3], &3, |m| {
+                        m.with_dyn(used[4], &4, |m| {
+                            for (i, key) in used.iter().enumerate() {
+                                assert_eq!(m.get_dyn(key), Some(&(i as u32)));
+                            }
+                            assert_eq!(m.get_dyn(&keys[12]), None);
+                        });
+                    });
+                });
+            });
+        });
+    });
+    assert!(bytes < 500 * size_of::<usize>(), "sparse: {bytes}");
+}
+
+ctxmap::schema!(SharedSchema);
+ctxmap::key!(SharedSchema {
+    KEY_OWNED: str = String::from("abc"),
+    shared KEY_SHARED: str = String::from("abc"),
+});
+
+#[test]
+fn shared_default() {
+    let _ = &CtxMap::<SharedSchema>::new()[&KEY_SHARED];
+    let owned = allocated(|| assert_eq!(&CtxMap::new()[&KEY_OWNED], "abc"));
+    let shared = allocated(|| assert_eq!(&CtxMap::new()[&KEY_SHARED], "abc"));
+    assert!(owned > 0, "owned: {owned}");
+    assert_eq!(shared, 0);
+}
+
+#[test]
+fn iter_no_alloc() {
+    let mut m = CtxMap::<SharedSchema>::new();
+    let _ = &m[&KEY_OWNED];
+    m.with(&KEY_SHARED, "xyz", |m| {
+        assert_eq!(m.iter().count(), 2);
+        let bytes = allocated(|| {
+            for (_, _, value) in m.iter() {
+                assert!(value.downcast_ref::<str>().is_some());
+            }
+        });
+        assert_eq!(bytes, 0);
+    });
+}
+
+#[test]
+fn dense_map_size() {
+    let size = size_of::<CtxMap<DenseSchema>>();
+    assert!(size <= 11 * size_of::<usize>(), "dense: {size}");
+    assert!(size < size_of::<CtxMap<SparseSchema>>());
+}
@@ -1,5 +1,12 @@
-use ctxmap::CtxMap;
-use std::{fmt::Display, mem::swap};
+use ctxmap::{CtxMap, DynKey, Entry, EntryState, FutureExt, InitError};
+use std::{
+    fmt::Display,
+    future::Future,
+    mem::swap,
+    panic::catch_unwind,
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+};
 
 ctxmap::schema!(Schema);
 ctxmap::key!(Schema { KEY_X: u8 = 10 });
@@ -140,3 +147,1113 @@ fn test_swap() {
     assert_eq!(m0[&KEY_A], 1);
     assert_eq!(m1[&KEY_A], 1);
 }
+
+#[test]
+fn current() {
+    let mut m = CtxMap::new();
+    assert_eq!(ctxmap::current(|m| m[&KEY_X]), 10);
+    m.with(&KEY_X, &20, |m| {
+        assert_eq!(ctxmap::current(|m| m[&KEY_X]), 10);
+        m.enter(|| {
+            assert_eq!(ctxmap::current(|m| m[&KEY_X]), 20);
+        });
+        m.with(&KEY_X, &30, |m| {
+            m.enter(|| {
+                assert_eq!(ctxmap::current(|m| m[&KEY_X]), 30);
+            });
+        });
+    });
+    assert_eq!(ctxmap::current(|m| m[&KEY_X]), 10);
+}
+
+thread_local! {
+    static CURRENT_INIT_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+ctxmap::key!(Schema {
+    CURRENT_COUNTED: u8 = {
+        CURRENT_INIT_COUNT.set(CURRENT_INIT_COUNT.get() + 1);
+        1
+    },
+});
+
+#[test]
+fn current_empty() {
+    assert_eq!(ctxmap::current(|m| m[&CURRENT_COUNTED]), 1);
+    assert_eq!(ctxmap::current(|m| m[&CURRENT_COUNTED]), 1);
+    assert_eq!(CURRENT_INIT_COUNT.get(), 1);
+}
+
+#[test]
+fn current_nest() {
+    let mut m0 = CtxMap::new();
+    let mut m1 = CtxMap::new();
+    m0.with(&KEY_X, &20, |m0| {
+        m0.enter(|| {
+            m1.with(&KEY_X, &30, |m1| {
+                m1.enter(|| {
+                    assert_eq!(ctxmap::current(|m| m[&KEY_X]), 30);
+                });
+            });
+            assert_eq!(ctxmap::current(|m| m[&KEY_X]), 20);
+        });
+    });
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(value) = f.as_mut().poll(&mut cx) {
+            return value;
+        }
+    }
+}
+
+#[derive(Default)]
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn bind_future() {
+    let mut m = CtxMap::new();
+    let value = block_on(m.bind_future(&KEY_X, &20, async {
+        let a = ctxmap::current(|m| m[&KEY_X]);
+        YieldNow::default().await;
+        let b = ctxmap::current(|m| m[&KEY_X]);
+        (a, b)
+    }));
+    assert_eq!(value, (20, 20));
+    assert_eq!(m[&KEY_X], 10);
+}
+
+#[test]
+fn bind_future_restore_between_polls() {
+    let mut m = CtxMap::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut f = pin!(m.bind_future(&KEY_X, &20, YieldNow::default()));
+    assert_eq!(f.as_mut().poll(&mut cx), Poll::Pending);
+    assert_eq!(ctxmap::current(|m| m[&KEY_X]), 10);
+    assert_eq!(f.as_mut().poll(&mut cx), Poll::Ready(()));
+}
+
+#[test]
+fn with_ctx() {
+    let mut m = CtxMap::new();
+    m.with(&KEY_X, &20, |m| {
+        let value = block_on(
+            async {
+                YieldNow::default().await;
+                ctxmap::current(|m| m[&KEY_X])
+            }
+            .with_ctx(m.view()),
+        );
+        assert_eq!(value, 20);
+    });
+}
+
+#[test]
+fn with_async() {
+    let mut m = CtxMap::new();
+    let value = block_on(m.with_async(&KEY_X, &20, async |m| {
+        assert_eq!(m[&KEY_X], 20);
+        YieldNow::default().await;
+        let a = m[&KEY_X];
+        let b = m
+            .with_async(&KEY_X, &30, async |m| {
+                YieldNow::default().await;
+                m[&KEY_X]
+            })
+            .await;
+        (a, b, m[&KEY_X])
+    }));
+    assert_eq!(value, (20, 30, 20));
+    assert_eq!(m[&KEY_X], 10);
+}
+
+#[test]
+fn with_async_drop() {
+    let mut m = CtxMap::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    {
+        let mut f = pin!(m.with_async(&KEY_X, &20, async |_| YieldNow::default().await));
+        assert_eq!(f.as_mut().poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(m[&KEY_X], 10);
+}
+
+#[test]
+fn with_async_computed() {
+    let mut m = CtxMap::new();
+    let value = block_on(m.with_async(&COMPUTED_A, &10, async |m| {
+        let sum = &m[&COMPUTED_SUM];
+        YieldNow::default().await;
+        let double = &m[&COMPUTED_DOUBLE];
+        YieldNow::default().await;
+        (*sum, *double)
+    }));
+    assert_eq!(value, (12, 24));
+    assert_eq!(m[&COMPUTED_SUM], 3);
+    assert_eq!(m[&COMPUTED_DOUBLE], 6);
+}
+
+/// Polls `f` with a deeper stack than the caller.
+fn poll_deep<F: Future>(f: Pin<&mut F>, cx: &mut Context, depth: usize) -> Poll<F::Output> {
+    if depth == 0 {
+        f.poll(cx)
+    } else {
+        let p = poll_deep(f, cx, depth - 1);
+        std::hint::black_box([0u8; 256]);
+        p
+    }
+}
+
+/// Overwrites the stack used by [`poll_deep`].
+fn clobber_stack(depth: usize) {
+    let bytes = std::hint::black_box([0xffu8; 256]);
+    if depth > 0 {
+        clobber_stack(depth - 1);
+    }
+    std::hint::black_box(bytes);
+}
+
+#[test]
+fn with_async_stack() {
+    let mut m = CtxMap::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut f = pin!(m.with_async(&KEY_X, &20, async |m| {
+        let stack = m.stack(&KEY_X);
+        YieldNow::default().await;
+        stack.copied().collect::<Vec<_>>()
+    }));
+    assert_eq!(poll_deep(f.as_mut(), &mut cx, 8), Poll::Pending);
+    clobber_stack(16);
+    assert_eq!(f.poll(&mut cx), Poll::Ready(vec![20, 10]));
+}
+
+#[test]
+fn with_async_drop_computed() {
+    let mut m = CtxMap::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    {
+        let mut f = pin!(m.with_async(&COMPUTED_A, &10, async |m| {
+            let sum = &m[&COMPUTED_SUM];
+            YieldNow::default().await;
+            *sum
+        }));
+        assert_eq!(f.as_mut().poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(m[&COMPUTED_SUM], 3);
+}
+
+#[test]
+fn unwind() {
+    let mut m = CtxMap::new();
+    let r = catch_unwind(|| m[&KEY_X]);
+    assert_eq!(r.unwrap(), 10);
+
+    let mut v = m.view();
+    let r = catch_unwind(move || {
+        v.with(&KEY_X, &20, |v| {
+            v.with(&KEY_X, &30, |_| panic!("unwind"));
+        });
+    });
+    assert!(r.is_err());
+    assert_eq!(m[&KEY_X], 10);
+}
+
+#[test]
+fn unwind_view() {
+    let mut m = CtxMap::new();
+    m.with(&KEY_X, &20, |m| {
+        let mut v = m.view();
+        let r = catch_unwind(move || {
+            v.with(&KEY_X, &30, |_| panic!("unwind"));
+        });
+        assert!(r.is_err());
+        assert_eq!(m[&KEY_X], 20);
+    });
+}
+
+ctxmap::schema!(sync SyncSchema);
+ctxmap::key!(SyncSchema {
+    SYNC_KEY_X: u8 = 10,
+    SYNC_KEY_Y: dyn Display + Sync = 5,
+    mut SYNC_MUT: String = String::from("abc"),
+});
+
+fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+#[test]
+fn sync_schema_send() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    m[&SYNC_MUT].push_str("def");
+    let m = std::thread::spawn(move || {
+        assert_eq!(m[&SYNC_KEY_X], 10);
+        m
+    })
+    .join()
+    .unwrap();
+    assert_eq!(&m[&SYNC_MUT], "abcdef");
+}
+
+#[test]
+fn sync_schema_sync() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    m.with(&SYNC_KEY_X, &20, |m| {
+        assert_send_sync(m);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    assert_eq!(m[&SYNC_KEY_X], 20);
+                    assert_eq!(m[&SYNC_KEY_Y].to_string(), "5");
+                    assert_eq!(&m[&SYNC_MUT], "abc");
+                });
+            }
+        });
+    });
+}
+
+#[test]
+fn sync_schema_future_send() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    assert_send_sync(&m.view());
+    let f = m.bind_future(&SYNC_KEY_X, &20, async {});
+    fn assert_send<T: Send>(_: &T) {}
+    assert_send(&f);
+}
+
+#[test]
+fn sync_schema_lock_size() {
+    let lock = size_of::<CtxMap<SyncSchema>>() - size_of::<CtxMap<Schema>>();
+    assert_eq!(lock, size_of::<std::sync::Mutex<()>>());
+}
+
+#[test]
+fn scope() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    m.with(&SYNC_KEY_X, &20, |m| {
+        m.scope(|s, m| {
+            let hs: Vec<_> = (0..4)
+                .map(|_| s.spawn(|m| (m[&SYNC_KEY_X], ctxmap::current(|m| m[&SYNC_KEY_X]))))
+                .collect();
+            for h in hs {
+                assert_eq!(h.join().unwrap(), (20, 20));
+            }
+            assert_eq!(m[&SYNC_KEY_X], 20);
+        });
+    });
+}
+
+ctxmap::key!(Schema {
+    clone CLONE_X: u8 = 10,
+    clone CLONE_S: String,
+});
+
+#[test]
+fn snapshot() {
+    let mut m = CtxMap::new();
+    let s = m.with(&CLONE_X, &20, |m| {
+        m.with(&KEY_X, &20, |m| {
+            m.with(&CLONE_S, &String::from("abc"), |m| m.snapshot())
+        })
+    });
+    assert!(!s.is_empty());
+
+    let mut m = CtxMap::new();
+    m.with_snapshot(&s, |m| {
+        assert_eq!(m[&CLONE_X], 20);
+        assert_eq!(m[&CLONE_S], "abc");
+        assert_eq!(m[&KEY_X], 10);
+    });
+    assert_eq!(m[&CLONE_X], 10);
+    assert_eq!(m.get(&CLONE_S), None);
+}
+
+#[test]
+fn snapshot_empty() {
+    let m = CtxMap::<Schema>::new();
+    assert!(m.snapshot().is_empty());
+}
+
+#[test]
+fn snapshot_with_mut() {
+    let mut m = CtxMap::new();
+    let s = m.with_mut(&CLONE_X, &mut 20, |m| m.snapshot());
+    m.with_snapshot(&s, |m| assert_eq!(m[&CLONE_X], 20));
+}
+
+ctxmap::key!(SyncSchema {
+    clone SYNC_CLONE: String,
+});
+
+#[test]
+fn snapshot_send() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    let s = m.with(&SYNC_CLONE, &String::from("abc"), |m| m.snapshot());
+    std::thread::spawn(move || {
+        let mut m = CtxMap::new();
+        m.with_snapshot(&s, |m| assert_eq!(m[&SYNC_CLONE], "abc"));
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn with_all() {
+    let mut m = CtxMap::new();
+    m.with_all(((&KEY_X, &20), (&KEY_STR, "xyz"), (&MUT_1, &mut 30)), |m| {
+        assert_eq!(m[&KEY_X], 20);
+        assert_eq!(&m[&KEY_STR], "xyz");
+        assert_eq!(m[&MUT_1], 30);
+        m[&MUT_1] = 40;
+        assert_eq!(m[&MUT_1], 40);
+    });
+    assert_eq!(m[&KEY_X], 10);
+    assert_eq!(&m[&KEY_STR], "abc");
+    assert_eq!(m[&MUT_1], 10);
+}
+
+#[test]
+fn with_all_single() {
+    let mut m = CtxMap::new();
+    m.with_all((&KEY_X, &20), |m| assert_eq!(m[&KEY_X], 20));
+    m.with_all(((&KEY_X, &mut 30),), |m| assert_eq!(m[&KEY_X], 30));
+    assert_eq!(m[&KEY_X], 10);
+}
+
+#[test]
+fn with_all_same_key() {
+    let mut m = CtxMap::new();
+    m.with(&KEY_X, &20, |m| {
+        m.with_all(((&KEY_X, &30), (&KEY_X, &40)), |m| {
+            assert_eq!(m[&KEY_X], 40);
+        });
+        assert_eq!(m[&KEY_X], 20);
+    });
+}
+
+#[test]
+fn stack() {
+    let mut m = CtxMap::new();
+    assert_eq!(m.stack(&KEY_X).copied().collect::<Vec<_>>(), [10]);
+    m.with(&KEY_X, &20, |m| {
+        m.with(&KEY_X, &30, |m| {
+            assert_eq!(m.stack(&KEY_X).copied().collect::<Vec<_>>(), [30, 20, 10]);
+        });
+        assert_eq!(m.stack(&KEY_X).copied().collect::<Vec<_>>(), [20, 10]);
+    });
+}
+
+#[test]
+fn stack_no_default() {
+    let mut m = CtxMap::new();
+    assert_eq!(m.stack(&KEY_MANY_0).count(), 0);
+    m.with(&KEY_MANY_0, &1, |m| {
+        m.with(&KEY_MANY_0, &2, |m| {
+            assert_eq!(m.stack(&KEY_MANY_0).copied().collect::<Vec<_>>(), [2, 1]);
+        });
+    });
+}
+
+#[test]
+fn stack_with_all() {
+    let mut m = CtxMap::new();
+    m.with_all(((&KEY_X, &20), (&KEY_X, &30)), |m| {
+        assert_eq!(m.stack(&KEY_X).copied().collect::<Vec<_>>(), [30, 20, 10]);
+    });
+}
+
+ctxmap::key!(Schema {
+    acc ACC_PATH: [String],
+    acc ACC_X: [u8] = [1, 2],
+});
+
+#[test]
+fn with_append() {
+    let mut m = CtxMap::new();
+    assert!(m[&ACC_PATH].is_empty());
+    m.with_append(&ACC_PATH, "a".to_string(), |m| {
+        m.with_append(&ACC_PATH, "b".to_string(), |m| {
+            assert_eq!(m[&ACC_PATH], ["a", "b"]);
+        });
+        assert_eq!(m[&ACC_PATH], ["a"]);
+    });
+    assert!(m[&ACC_PATH].is_empty());
+}
+
+#[test]
+fn with_append_default() {
+    let mut m = CtxMap::new();
+    m.with_append(&ACC_X, 3, |m| {
+        assert_eq!(m[&ACC_X], [1, 2, 3]);
+    });
+}
+
+#[test]
+fn with_append_after_with() {
+    let mut m = CtxMap::new();
+    m.with(&ACC_X, &[5][..], |m| {
+        m.with_append(&ACC_X, 6, |m| {
+            assert_eq!(m[&ACC_X], [5, 6]);
+        });
+    });
+}
+
+ctxmap::key!(Schema {
+    CTX_BASE: u32 = 10,
+    CTX_DERIVED: u32 = |m| m[&CTX_BASE] * 3,
+    mut CTX_MUT_DERIVED: u32 = |m| m[&CTX_BASE] + 1,
+    CTX_CYCLE_A: u32 = |m| m[&CTX_CYCLE_B],
+    CTX_CYCLE_B: u32 = |m| m[&CTX_CYCLE_A],
+    CTX_TRY_CYCLE_A: u32 = try |m| Ok::<_, InitError>(*m.try_get(&CTX_TRY_CYCLE_B)?.unwrap()),
+    CTX_TRY_CYCLE_B: u32 = try |m| Ok::<_, InitError>(*m.try_get(&CTX_TRY_CYCLE_A)?.unwrap()),
+});
+
+#[test]
+fn context_default() {
+    let m = CtxMap::new();
+    assert_eq!(m[&CTX_DERIVED], 30);
+}
+
+#[test]
+fn context_default_with() {
+    let mut m = CtxMap::new();
+    m.with(&CTX_BASE, &5, |m| {
+        assert_eq!(m[&CTX_DERIVED], 15);
+        assert_eq!(m.get_entry(&CTX_DERIVED), Entry::Default(&15));
+    });
+    assert_eq!(m[&CTX_DERIVED], 30);
+}
+
+#[test]
+fn context_default_with_mut() {
+    let mut m = CtxMap::new();
+    m.with(&CTX_BASE, &5, |m| {
+        *m.get_mut(&CTX_MUT_DERIVED).unwrap() += 1;
+        assert_eq!(m[&CTX_MUT_DERIVED], 7);
+        assert!(format!("{m:?}").contains("CTX_MUT_DERIVED: Default(7)"));
+    });
+    assert!(format!("{m:?}").contains("CTX_MUT_DERIVED: Uninitialized"));
+    assert_eq!(m[&CTX_MUT_DERIVED], 11);
+}
+
+#[test]
+fn context_default_cycle() {
+    let m = CtxMap::new();
+    assert!(format!("{m:?}").contains("CTX_CYCLE_A: Uninitialized"));
+    let e = catch_unwind(|| m[&CTX_CYCLE_A]).unwrap_err();
+    let message = e.downcast_ref::<String>().unwrap();
+    assert!(message.contains("cycle detected at key `CTX_CYCLE_A: u32` of schema `Schema`"));
+    let e = catch_unwind(|| m[&CTX_CYCLE_B]).unwrap_err();
+    let message = e.downcast_ref::<String>().unwrap();
+    assert!(message.contains("cycle detected at key `CTX_CYCLE_B: u32`"));
+}
+
+#[test]
+fn context_default_cycle_try_get() {
+    let m = CtxMap::new();
+    let e = m.try_get(&CTX_TRY_CYCLE_A).unwrap_err();
+    let line = CTX_TRY_CYCLE_A.info().line();
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "failed to initialize the default value: cycle detected at key `CTX_TRY_CYCLE_A: u32` \
+             of schema `Schema` defined at {}:{line}",
+            file!()
+        )
+    );
+    assert!(m.try_get(&CTX_TRY_CYCLE_B).is_err());
+    assert_eq!(m.try_get(&CTX_DERIVED).unwrap(), Some(&30));
+}
+
+ctxmap::key!(Schema {
+    COMPUTED_A: u32 = 1,
+    COMPUTED_B: u32 = 2,
+    computed COMPUTED_SUM: u32 = |m| {
+        COMPUTED_COUNT.set(COMPUTED_COUNT.get() + 1);
+        m[&COMPUTED_A] + m[&COMPUTED_B]
+    },
+    computed COMPUTED_DOUBLE: u32 = |m| m[&COMPUTED_SUM] * 2,
+});
+thread_local! {
+    static COMPUTED_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[test]
+fn computed() {
+    let count = || COMPUTED_COUNT.get();
+    let mut m = CtxMap::new();
+    assert_eq!(m[&COMPUTED_SUM], 3);
+    assert_eq!(m[&COMPUTED_SUM], 3);
+    assert_eq!(count(), 1);
+    m.with(&KEY_X, &0, |m| {
+        assert_eq!(m[&COMPUTED_SUM], 3);
+        assert_eq!(count(), 1);
+    });
+    m.with(&COMPUTED_A, &10, |m| {
+        assert_eq!(m[&COMPUTED_SUM], 12);
+        assert_eq!(m[&COMPUTED_DOUBLE], 24);
+        m.with(&COMPUTED_B, &20, |m| {
+            assert_eq!(m[&COMPUTED_SUM], 30);
+            assert_eq!(m[&COMPUTED_DOUBLE], 60);
+        });
+        assert_eq!(m[&COMPUTED_SUM], 12);
+        assert_eq!(m[&COMPUTED_DOUBLE], 24);
+        assert_eq!(count(), 3);
+    });
+    assert_eq!(m[&COMPUTED_SUM], 3);
+    assert_eq!(m[&COMPUTED_DOUBLE], 6);
+    assert_eq!(count(), 3);
+}
+
+#[test]
+fn computed_override() {
+    let mut m = CtxMap::new();
+    m.with(&COMPUTED_SUM, &100, |m| {
+        assert_eq!(m[&COMPUTED_DOUBLE], 200);
+    });
+    assert_eq!(m[&COMPUTED_DOUBLE], 6);
+}
+
+ctxmap::key!(Schema {
+    shared SHARED_STR: str = "abc",
+    shared SHARED_FROM: u32 = |m| m[&COMPUTED_A] + 1,
+    shared SHARED_CYCLE_A: u32 = |m| m[&SHARED_CYCLE_B],
+    shared SHARED_CYCLE_B: u32 = |m| m[&SHARED_CYCLE_A],
+});
+
+#[test]
+fn shared() {
+    let addr = |m: &CtxMap<Schema>| m[&SHARED_STR].as_ptr() as usize;
+    let mut m1 = CtxMap::new();
+    let m2 = CtxMap::new();
+    assert_eq!(addr(&m1), addr(&m2));
+    let other = std::thread::spawn(move || addr(&CtxMap::new()));
+    assert_eq!(other.join().unwrap(), addr(&m1));
+    m1.with(&COMPUTED_A, &10, |m| {
+        assert_eq!(m[&SHARED_FROM], 2);
+        m.with(&SHARED_FROM, &5, |m| assert_eq!(m[&SHARED_FROM], 5));
+    });
+    assert_eq!(m2.get_entry(&SHARED_FROM), Entry::Default(&2));
+}
+
+#[test]
+fn shared_cycle() {
+    let m = CtxMap::new();
+    let e = catch_unwind(|| m[&SHARED_CYCLE_A]).unwrap_err();
+    let message = e.downcast_ref::<String>().unwrap();
+    assert!(message.contains("cycle detected at key `SHARED_CYCLE_A: u32`"));
+    let e = catch_unwind(|| m.try_get(&SHARED_CYCLE_B).is_err()).unwrap_err();
+    let message = e.downcast_ref::<String>().unwrap();
+    assert!(message.contains("cycle detected at key `SHARED_CYCLE_B: u32`"));
+}
+
+thread_local! {
+    static TRY_FAIL: std::cell::Cell<bool> = const { std::cell::Cell::new(true) };
+}
+
+ctxmap::key!(Schema {
+    TRY_OK: u32 = try "10".parse::<u32>(),
+    TRY_ERR: u32 = try "x".parse::<u32>(),
+    TRY_RETRY: u32 = try if TRY_FAIL.get() { Err("fail") } else { Ok(5) },
+    mut TRY_MUT: String = try |m| Ok::<_, String>(m[&TRY_OK].to_string()),
+    TRY_NESTED: u32 = try |m| Ok::<_, InitError>(m.try_get(&TRY_ERR)?.unwrap() + 1),
+});
+
+#[test]
+fn try_get() {
+    let m = CtxMap::new();
+    assert_eq!(m.try_get(&TRY_OK).unwrap(), Some(&10));
+    assert_eq!(m.try_get(&KEY_MANY_0).unwrap(), None);
+    let e = m.try_get(&TRY_ERR).unwrap_err();
+    assert!(std::error::Error::source(&e)
+        .unwrap()
+        .is::<std::num::ParseIntError>());
+}
+
+#[test]
+fn try_get_nested() {
+    let m = CtxMap::new();
+    let e = m.try_get(&TRY_NESTED).unwrap_err();
+    assert!(std::error::Error::source(&e)
+        .unwrap()
+        .is::<std::num::ParseIntError>());
+    assert!(m.try_get(&TRY_NESTED).is_err());
+}
+
+#[test]
+fn try_get_retry() {
+    let m = CtxMap::new();
+    assert_eq!(
+        m.try_get(&TRY_RETRY).unwrap_err().to_string(),
+        "failed to initialize the default value: fail"
+    );
+    TRY_FAIL.set(false);
+    assert_eq!(m.try_get(&TRY_RETRY).unwrap(), Some(&5));
+}
+
+#[test]
+fn try_get_mut() {
+    let mut m = CtxMap::new();
+    m.get_mut(&TRY_MUT).unwrap().push('!');
+    assert_eq!(m[&TRY_MUT], "10!");
+}
+
+#[test]
+#[should_panic(expected = "failed to initialize the default value")]
+fn try_get_panic() {
+    let m = CtxMap::new();
+    m.get(&TRY_ERR);
+}
+
+#[test]
+fn get_entry() {
+    let mut m = CtxMap::new();
+    assert_eq!(m.get_entry(&KEY_X), Entry::Default(&10));
+    assert_eq!(m.get_entry(&KEY_MANY_0), Entry::Missing);
+    assert_eq!(m.get_entry(&KEY_MANY_0).value(), None);
+    m.with(&KEY_X, &20, |m| {
+        assert_eq!(m.get_entry(&KEY_X), Entry::Bound(&20));
+        assert_eq!(m.get_entry(&KEY_X).value(), Some(&20));
+    });
+    m.with(&KEY_STR, "xyz", |m| {
+        assert_eq!(m.get_entry(&KEY_STR), Entry::Bound("xyz"));
+    });
+}
+
+#[test]
+fn is_overridden() {
+    let mut m = CtxMap::new();
+    assert!(!m.is_overridden(&KEY_X));
+    m.with(&KEY_X, &20, |m| {
+        assert!(m.is_overridden(&KEY_X));
+        assert!(!m.is_overridden(&KEY_STR));
+    });
+    assert!(!m.is_overridden(&KEY_X));
+}
+
+#[test]
+fn has_default() {
+    let mut m = CtxMap::new();
+    assert!(m.has_default(&KEY_X));
+    assert!(!m.has_default(&KEY_MANY_0));
+    m.with(&KEY_MANY_0, &1, |m| {
+        assert!(!m.has_default(&KEY_MANY_0));
+    });
+}
+
+#[test]
+fn key_info() {
+    let info = KEY_X.info();
+    assert_eq!(info.name(), "KEY_X");
+    assert_eq!(info.schema(), "Schema");
+    assert_eq!(info.type_name(), "u8");
+    assert_eq!(info.file(), file!());
+    assert_eq!(SYNC_KEY_Y.info().type_name(), "dyn Display + Sync");
+}
+
+#[test]
+fn require() {
+    let mut m = CtxMap::new();
+    assert_eq!(m.require(&KEY_X).unwrap(), &10);
+    let e = m.require(&KEY_MANY_0).unwrap_err();
+    assert_eq!(e.key(), KEY_MANY_0.info());
+    let line = KEY_MANY_0.info().line();
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "no value for key `KEY_MANY_0: u8` of schema `Schema` defined at {}:{line}",
+            file!()
+        )
+    );
+    m.with(&KEY_MANY_0, &1, |m| {
+        assert_eq!(m.require(&KEY_MANY_0).unwrap(), &1);
+    });
+}
+
+#[test]
+#[should_panic(expected = "no value for key `KEY_MANY_0: u8`")]
+fn index_missing() {
+    let m = CtxMap::new();
+    let _ = m[&KEY_MANY_0];
+}
+
+#[test]
+#[should_panic(expected = "no value for key `MUT_0: u8`")]
+fn index_mut_missing() {
+    let mut m = CtxMap::new();
+    m[&MUT_0] = 1;
+}
+
+ctxmap::schema!(KeysSchema);
+ctxmap::key!(KeysSchema {
+    /// Key A.
+    ///
+    /// Second line.
+    #[allow(dead_code)]
+    KEYS_A: u8 = 1,
+    mut KEYS_B: str,
+    acc KEYS_C: [u8],
+    #[cfg(any())]
+    KEYS_D: u8,
+});
+
+#[test]
+fn schema_keys() {
+    use ctxmap::Schema as _;
+    let keys = KeysSchema::keys();
+    let names: Vec<_> = keys.iter().map(|k| k.name()).collect();
+    assert_eq!(names, ["KEYS_A", "KEYS_B", "KEYS_C"]);
+    assert_eq!(keys[0].doc(), "Key A.\n\nSecond line.");
+    assert!(keys[0].has_default());
+    assert!(!keys[0].is_mut());
+    assert_eq!(keys[1].doc(), "");
+    assert!(!keys[1].has_default());
+    assert!(keys[1].is_mut());
+    assert_eq!(keys[1].type_name(), "str");
+    assert!(keys[2].has_default());
+    assert_eq!(keys[0], KEYS_A.info());
+}
+
+struct NoDebug;
+
+ctxmap::schema!(DebugSchema);
+ctxmap::key!(DebugSchema {
+    DEBUG_A: u8 = 1,
+    DEBUG_B: str,
+    secret DEBUG_C: str = "secret",
+    DEBUG_D: NoDebug = NoDebug,
+    #[debug(fmt_no_debug)]
+    DEBUG_E: NoDebug = NoDebug,
+    DEBUG_F: u8 = try "x".parse::<u8>(),
+});
+
+fn fmt_no_debug(_: &NoDebug, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str("NoDebug")
+}
+
+#[test]
+fn debug() {
+    let mut m = CtxMap::<DebugSchema>::new();
+    m.with(&DEBUG_B, "abc", |m| {
+        m.with(&DEBUG_C, "xyz", |m| {
+            assert_eq!(
+                format!("{m:?}"),
+                "CtxMap { DEBUG_A: Uninitialized, DEBUG_B: Bound(\"abc\"), DEBUG_C: Bound(<redacted>), \
+                 DEBUG_D: Uninitialized, DEBUG_E: Uninitialized, DEBUG_F: Uninitialized }"
+            );
+            let _ = m[&DEBUG_A];
+            let _ = &m[&DEBUG_D];
+            let _ = &m[&DEBUG_E];
+            assert_eq!(
+                format!("{m:?}"),
+                "CtxMap { DEBUG_A: Default(1), DEBUG_B: Bound(\"abc\"), DEBUG_C: Bound(<redacted>), \
+                 DEBUG_D: Default(..), DEBUG_E: Default(NoDebug), DEBUG_F: Uninitialized }"
+            );
+        });
+    });
+    assert!(format!("{m:?}").contains("DEBUG_B: Missing"));
+}
+
+#[test]
+fn iter() {
+    let mut m = CtxMap::<DebugSchema>::new();
+    assert_eq!(m.iter().count(), 0);
+    assert_eq!(m[&DEBUG_A], 1);
+    m.with(&DEBUG_B, "abc", |m| {
+        m.with(&DEBUG_C, "xyz", |m| {
+            let entries: Vec<_> = m.iter().collect();
+            let names: Vec<_> = entries.iter().map(|e| e.0.name()).collect();
+            assert_eq!(names, ["DEBUG_A", "DEBUG_B", "DEBUG_C"]);
+            let states: Vec<_> = entries.iter().map(|e| e.1).collect();
+            assert_eq!(
+                states,
+                [EntryState::Default, EntryState::Bound, EntryState::Bound]
+            );
+            assert_eq!(entries[0].2.downcast_ref::<u8>(), Some(&1));
+            assert_eq!(entries[0].2.downcast_ref::<u16>(), None);
+            assert_eq!(entries[1].2.downcast_ref::<str>(), Some("abc"));
+            assert_eq!(format!("{:?}", entries[1].2), "\"abc\"");
+            assert_eq!(format!("{:?}", entries[2].2), "<redacted>");
+        });
+    });
+    assert_eq!(m.iter().count(), 1);
+}
+
+#[test]
+fn iter_mut_key() {
+    let mut m = CtxMap::new();
+    m.with_mut(&MUT_0, &mut 5, |m| {
+        let value = m.iter().find(|e| e.0.name() == "MUT_0").unwrap().2;
+        assert_eq!(value.downcast_ref::<u8>(), Some(&5));
+    });
+}
+
+#[test]
+fn iter_type_and_dyn_excluded() {
+    let mut m = CtxMap::<DebugSchema>::new();
+    let key = DynKey::<DebugSchema, u8>::new();
+    m.with_type(&5u32, |m| {
+        m.with_dyn(&key, &6, |m| {
+            assert_eq!(m.get_type::<u32>(), Some(&5));
+            assert_eq!(m.iter().count(), 0);
+            assert!(!format!("{m:?}").contains('5'));
+            assert!(!format!("{m:?}").contains('6'));
+        });
+    });
+}
+
+#[derive(Debug, PartialEq)]
+struct DbPool(u32);
+
+#[test]
+fn with_type() {
+    let mut m = CtxMap::<Schema>::new();
+    assert_eq!(m.get_type::<DbPool>(), None);
+    m.with_type(&DbPool(1), |m| {
+        assert_eq!(m.get_type::<DbPool>(), Some(&DbPool(1)));
+        m.with_type(&DbPool(2), |m| {
+            assert_eq!(m.get_type::<DbPool>(), Some(&DbPool(2)));
+        });
+        assert_eq!(m.get_type::<DbPool>(), Some(&DbPool(1)));
+        m.with_type("abc", |m| {
+            assert_eq!(m.get_type::<str>(), Some("abc"));
+            assert_eq!(m.get_type::<DbPool>(), Some(&DbPool(1)));
+        });
+    });
+    assert_eq!(m.get_type::<DbPool>(), None);
+    assert_eq!(
+        m.get_type::<DbPool>(),
+        CtxMap::<SyncSchema>::new().get_type()
+    );
+}
+
+#[test]
+fn with_type_per_schema() {
+    let mut m0 = CtxMap::<Schema>::new();
+    let m1 = CtxMap::<SyncSchema>::new();
+    m0.with_type(&DbPool(1), |_| {
+        assert_eq!(m1.get_type::<DbPool>(), None);
+    });
+}
+
+#[test]
+fn get_type_or_default() {
+    let mut m = CtxMap::<SyncSchema>::new();
+    assert_eq!(m.get_type_or_default::<String>(), "");
+    m.with_type(&String::from("abc"), |m| {
+        assert_eq!(m.get_type_or_default::<String>(), "abc");
+    });
+    assert_eq!(m.get_type_or_default::<String>(), "");
+}
+
+#[test]
+fn dyn_key() {
+    let key0 = DynKey::<Schema, u8>::new();
+    let key1 = DynKey::<Schema, str>::new();
+    let mut m = CtxMap::new();
+    assert_eq!(m.get_dyn(&key0), None);
+    m.with_dyn(&key0, &1, |m| {
+        m.with_dyn(&key1, "abc", |m| {
+            m.with_dyn(&key0, &2, |m| {
+                assert_eq!(m.get_dyn(&key0), Some(&2));
+                assert_eq!(m.get_dyn(&key1), Some("abc"));
+            });
+            assert_eq!(m.get_dyn(&key0), Some(&1));
+        });
+        assert_eq!(m.get_dyn(&key1), None);
+    });
+    assert_eq!(m.get_dyn(&key0), None);
+}
+
+#[test]
+fn dyn_key_clone() {
+    let key = DynKey::<Schema, u8>::with_default(|_| 5);
+    let m = CtxMap::new();
+    assert_eq!(m.get_dyn(&key), Some(&5));
+    let key2 = key.clone();
+    drop(key);
+    assert_eq!(m.get_dyn(&key2), Some(&5));
+}
+
+ctxmap::schema!(DynSchema);
+
+#[test]
+fn dyn_key_reuse() {
+    let m = CtxMap::<DynSchema>::new();
+    let key = DynKey::<DynSchema, String>::with_default(|_| "old".to_string());
+    assert_eq!(m.get_dyn(&key).unwrap(), "old");
+    drop(key);
+
+    let key = DynKey::<DynSchema, String>::with_default(|_| "new".to_string());
+    assert_eq!(m.get_dyn(&key).unwrap(), "new");
+    drop(key);
+
+    let key = DynKey::<DynSchema, String>::new();
+    assert_eq!(m.get_dyn(&key), None);
+}
+
+#[test]
+fn get_by_name() {
+    let mut m = CtxMap::<DebugSchema>::new();
+    let a = m.get_by_name("DEBUG_A").unwrap();
+    assert_eq!(a.downcast_ref::<u8>(), Some(&1));
+    assert_eq!(a.display().unwrap().to_string(), "1");
+    assert!(m.get_by_name("DEBUG_B").is_none());
+    m.with(&DEBUG_B, "abc", |m| {
+        let b = m.get_by_name("DEBUG_B").unwrap();
+        assert_eq!(b.downcast_ref::<str>(), Some("abc"));
+        assert_eq!(format!("{b:?}"), "\"abc\"");
+        assert_eq!(b.display().unwrap().to_string(), "abc");
+    });
+    let c = m.get_by_name("DEBUG_C").unwrap();
+    assert_eq!(c.downcast_ref::<str>(), Some("secret"));
+    assert_eq!(c.display().unwrap().to_string(), "<redacted>");
+    let d = m.get_by_name("DEBUG_D").unwrap();
+    assert!(d.display().is_none());
+    assert!(m.get_by_name("DEBUG_X").is_none());
+}
+
+ctxmap::schema!(DupSchema);
+mod dup_a {
+    ctxmap::key!(super::DupSchema { pub KEY_DUP: u8 = 1 });
+}
+mod dup_b {
+    ctxmap::key!(super::DupSchema { pub KEY_DUP: u8 = 2 });
+}
+ctxmap::key!(DupSchema { DUP_OTHER: u8 = 3 });
+
+#[test]
+fn key_name_duplicate() {
+    let m = CtxMap::<DupSchema>::new();
+    assert_eq!(m[&DUP_OTHER], 3);
+    assert_eq!(m[&dup_a::KEY_DUP], 1);
+    assert_eq!(m[&dup_b::KEY_DUP], 2);
+    assert!(m.get_by_name("KEY_DUP").is_none());
+    let other = m.get_by_name("DUP_OTHER").unwrap();
+    assert_eq!(other.downcast_ref::<u8>(), Some(&3));
+}
+
+ctxmap::schema!(IdSchema);
+ctxmap::key!(IdSchema {
+    ID_A: u8 = 1,
+    /// Key B.
+    #[id("id_b")]
+    #[debug(fmt_no_debug)]
+    ID_B: NoDebug = NoDebug,
+});
+
+#[test]
+fn key_id() {
+    assert_eq!(ID_A.info().id(), "test::ID_A");
+    assert_eq!(ID_B.info().id(), "id_b");
+    assert_eq!(ID_B.info().doc(), "Key B.");
+    let m = CtxMap::<IdSchema>::new();
+    assert_eq!(m[&ID_A], 1);
+    assert_eq!(
+        format!("{m:?}"),
+        "CtxMap { ID_A: Default(1), ID_B: Uninitialized }"
+    );
+}
+
+ctxmap::schema!(DupIdSchema);
+ctxmap::key!(DupIdSchema {
+    #[id("same")]
+    DUP_ID_A: u8 = 1,
+    #[id("same")]
+    DUP_ID_B: u8 = 2,
+});
+
+#[test]
+fn key_id_duplicate() {
+    let m = CtxMap::<DupIdSchema>::new();
+    let e = catch_unwind(|| m[&DUP_ID_A]).unwrap_err();
+    let message = e.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("duplicate key id `same` in schema `DupIdSchema`"));
+    for _ in 0..2 {
+        let e = catch_unwind(|| m.get_type::<u32>().is_some()).unwrap_err();
+        let message = e.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("duplicate key id `same` in schema `DupIdSchema`"));
+    }
+}
+
+ctxmap::schema!(SameLineSchema);
+
+macro_rules! same_line_keys {
+    ($($f:ident: $t:ty = $v:expr),*) => {
+        $(
+            fn $f(m: &CtxMap<SameLineSchema>) -> String {
+                ctxmap::key!(SameLineSchema { KEY_SAME_LINE: $t = $v });
+                m[&KEY_SAME_LINE].to_string()
+            }
+        )*
+    };
+}
+same_line_keys!(same_line_a: usize = 1, same_line_b: String = String::from("b"));
+
+#[test]
+fn key_same_line() {
+    let m = CtxMap::new();
+    assert_eq!(same_line_a(&m), "1");
+    assert_eq!(same_line_b(&m), "b");
+    assert_eq!(same_line_a(&m), "1");
+    assert!(m.get_by_name("KEY_SAME_LINE").is_none());
+}
+
+ctxmap::schema!(sparse SparseSchema);
+ctxmap::key!(SparseSchema {
+    SPARSE_0: u8 = 10,
+    SPARSE_1: u8,
+    SPARSE_2: str = "abc",
+    clone SPARSE_3: u8,
+    SPARSE_4: u8,
+    mut SPARSE_5: u8 = 20,
+});
+
+#[test]
+fn sparse() {
+    let mut m = CtxMap::<SparseSchema>::new();
+    assert_eq!(m[&SPARSE_0], 10);
+    assert_eq!(m.get(&SPARSE_1), None);
+    m[&SPARSE_5] += 1;
+    let bindings = (
+        (&SPARSE_0, &1),
+        (&SPARSE_1, &2),
+        (&SPARSE_2, "xyz"),
+        (&SPARSE_3, &4),
+        (&SPARSE_4, &5),
+    );
+    let snapshot = m.with_all(bindings, |m| {
+        assert_eq!(m[&SPARSE_0], 1);
+        assert_eq!(m[&SPARSE_1], 2);
+        assert_eq!(&m[&SPARSE_2], "xyz");
+        assert_eq!(m[&SPARSE_4], 5);
+        m.with_mut(&SPARSE_5, &mut 6, |m| {
+            m[&SPARSE_5] += 1;
+            assert_eq!(m[&SPARSE_5], 7);
+        });
+        assert_eq!(m[&SPARSE_5], 21);
+        m.snapshot()
+    });
+    assert_eq!(m[&SPARSE_0], 10);
+    assert_eq!(m.get(&SPARSE_1), None);
+    assert_eq!(&m[&SPARSE_2], "abc");
+    assert_eq!(m.get(&SPARSE_3), None);
+    m.with_snapshot(&snapshot, |m| {
+        assert_eq!(m[&SPARSE_3], 4);
+        assert_eq!(m.get(&SPARSE_4), None);
+    });
+}